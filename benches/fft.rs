@@ -0,0 +1,160 @@
+//! Time per hop of the morpher's FFT round trip, the way it was done over
+//! the whole complex spectrum with rustfft, against the way it's done now
+//! over the N/2+1 bins of a real spectrum with realfft.
+//!
+//! Each hop windows A and B, takes both to the frequency domain, goes
+//! through every bin to polar and back, and takes both branches back to the
+//! time domain, as `Morpher::morph` does around its own spectral work.
+//!
+//! Run with `cargo bench --bench fft`.
+
+use realfft::{num_complex::Complex32, RealFftPlanner};
+use rustfft::FftPlanner;
+use std::{
+    hint::black_box,
+    time::{Duration, Instant},
+};
+
+const WINDOW_SIZES: [usize; 4] = [256, 1024, 4096, 16384];
+const OVERLAP: usize = 4;
+/// Input run through at every window size, in samples.
+const INPUT_LENGTH: usize = 1 << 21;
+
+fn main() {
+    println!("window   complex      real");
+    for window_size in WINDOW_SIZES {
+        let complex = time_per_hop(window_size, ComplexPath::new(window_size));
+        let real = time_per_hop(window_size, RealPath::new(window_size));
+        println!("{window_size:>6} {complex:>9.1?} {real:>9.1?}");
+    }
+}
+
+/// One hop's worth of work, on frames of A and B that are already windowed.
+trait Path {
+    fn hop(&mut self, a: &[f32], b: &[f32]) -> (f32, f32);
+}
+
+fn time_per_hop(window_size: usize, mut path: impl Path) -> Duration {
+    let hop_length = window_size / OVERLAP;
+    let window: Vec<f32> = (0..window_size)
+        .map(|i| 0.5 - 0.5 * (std::f32::consts::TAU * i as f32 / window_size as f32).cos())
+        .collect();
+    let a: Vec<f32> = (0..INPUT_LENGTH)
+        .map(|i| (i as f32 * 0.031).sin())
+        .collect();
+    let b: Vec<f32> = (0..INPUT_LENGTH).map(|i| (i as f32 * 0.05).sin()).collect();
+    let mut frames = (vec![0.0; window_size], vec![0.0; window_size]);
+
+    let n_hops = (INPUT_LENGTH - window_size) / hop_length;
+    let start = Instant::now();
+    for hop in 0..n_hops {
+        let range = hop * hop_length..hop * hop_length + window_size;
+        for (frame, input) in [
+            (&mut frames.0, &a[range.clone()]),
+            (&mut frames.1, &b[range]),
+        ] {
+            for ((frame, input), window) in frame.iter_mut().zip(input).zip(&window) {
+                *frame = input * window;
+            }
+        }
+        black_box(path.hop(&frames.0, &frames.1));
+    }
+    start.elapsed() / n_hops as u32
+}
+
+/// Through the polar form and back, standing in for the spectral work.
+fn polar_round_trip(bins: &mut [Complex32]) {
+    for bin in bins {
+        let (mag, phase) = bin.to_polar();
+        *bin = Complex32::from_polar(mag, phase);
+    }
+}
+
+struct ComplexPath {
+    fft_fwd: std::sync::Arc<dyn rustfft::Fft<f32>>,
+    fft_inv: std::sync::Arc<dyn rustfft::Fft<f32>>,
+    spectra: (Vec<Complex32>, Vec<Complex32>),
+    scratch: Vec<Complex32>,
+}
+impl ComplexPath {
+    fn new(window_size: usize) -> Self {
+        let mut planner = FftPlanner::new();
+        let fft_fwd = planner.plan_fft_forward(window_size);
+        let fft_inv = planner.plan_fft_inverse(window_size);
+        let scratch_len = fft_fwd
+            .get_inplace_scratch_len()
+            .max(fft_inv.get_inplace_scratch_len());
+        Self {
+            fft_fwd,
+            fft_inv,
+            spectra: (
+                vec![Complex32::default(); window_size],
+                vec![Complex32::default(); window_size],
+            ),
+            scratch: vec![Complex32::default(); scratch_len],
+        }
+    }
+}
+impl Path for ComplexPath {
+    fn hop(&mut self, a: &[f32], b: &[f32]) -> (f32, f32) {
+        for (spectrum, frame) in [(&mut self.spectra.0, a), (&mut self.spectra.1, b)] {
+            for (bin, sample) in spectrum.iter_mut().zip(frame) {
+                *bin = Complex32::new(*sample, 0.0);
+            }
+            self.fft_fwd
+                .process_with_scratch(spectrum, &mut self.scratch);
+            polar_round_trip(spectrum);
+            self.fft_inv
+                .process_with_scratch(spectrum, &mut self.scratch);
+        }
+        (self.spectra.0[0].re, self.spectra.1[0].re)
+    }
+}
+
+struct RealPath {
+    fft_fwd: std::sync::Arc<dyn realfft::RealToComplex<f32>>,
+    fft_inv: std::sync::Arc<dyn realfft::ComplexToReal<f32>>,
+    frames: (Vec<f32>, Vec<f32>),
+    spectra: (Vec<Complex32>, Vec<Complex32>),
+    scratch: Vec<Complex32>,
+}
+impl RealPath {
+    fn new(window_size: usize) -> Self {
+        let mut planner = RealFftPlanner::new();
+        let fft_fwd = planner.plan_fft_forward(window_size);
+        let fft_inv = planner.plan_fft_inverse(window_size);
+        let scratch_len = fft_fwd.get_scratch_len().max(fft_inv.get_scratch_len());
+        Self {
+            frames: (vec![0.0; window_size], vec![0.0; window_size]),
+            spectra: (fft_fwd.make_output_vec(), fft_fwd.make_output_vec()),
+            scratch: vec![Complex32::default(); scratch_len],
+            fft_fwd,
+            fft_inv,
+        }
+    }
+}
+impl Path for RealPath {
+    fn hop(&mut self, a: &[f32], b: &[f32]) -> (f32, f32) {
+        for ((frame, spectrum), input) in [
+            (&mut self.frames.0, &mut self.spectra.0),
+            (&mut self.frames.1, &mut self.spectra.1),
+        ]
+        .into_iter()
+        .zip([a, b])
+        {
+            frame.copy_from_slice(input);
+            self.fft_fwd
+                .process_with_scratch(frame, spectrum, &mut self.scratch)
+                .unwrap();
+            polar_round_trip(spectrum);
+            //// the inverse wants purely real DC and Nyquist bins
+            let n_bins = spectrum.len();
+            spectrum[0].im = 0.0;
+            spectrum[n_bins - 1].im = 0.0;
+            self.fft_inv
+                .process_with_scratch(spectrum, frame, &mut self.scratch)
+                .unwrap();
+        }
+        (self.frames.0[0], self.frames.1[0])
+    }
+}
@@ -1,26 +1,370 @@
-use std::{num::NonZeroU32, sync::Arc};
+use std::{
+    num::NonZeroU32,
+    sync::{
+        atomic::{AtomicU64, AtomicU8, Ordering},
+        Arc,
+    },
+};
 
+use morpher::{
+    AnalysisConfig, CombineMode, MagnitudeLaw, MorphSettings, PhaseLocking, ResetScope,
+    SidechainFallback, VocoderSettings,
+};
 use nih_plug::{nih_export_vst3, prelude::*};
 use processor::Processor;
+use window::WindowFunction;
 
 mod dbug;
 mod morpher;
 mod processor;
+mod routing;
 mod util;
+mod window;
 
 struct MorphPlugin {
     params: Arc<MorphParams>,
     sample_rate: f32,
+    /// The latency last reported to the host.
+    reported_latency: u32,
 
-    processors: [Processor; 2],
+    /// One per main channel of the layout in use.
+    processors: Vec<Processor>,
+    param_blocks: ParamBlocks,
+    /// The sidechain mixed to match the main channels, one per processor,
+    /// then the B -> A branch coming back out.
+    sidechain: Vec<Vec<f32>>,
+    /// A and B mixed down to mono, for linked stereo to find onsets in.
+    link: (Vec<f32>, Vec<f32>),
+    /// The stereo mode the processors' state belongs to.
+    stereo_mode: StereoMode,
+    sidechain_status: Arc<AtomicSidechainStatus>,
+    /// Whether the sidechain has carried any signal since `initialize`.
+    sidechain_seen: bool,
+    /// NaNs and infinities recovered from by all the processors, for the UI
+    /// to show.
+    recovered_events: Arc<AtomicU64>,
+    /// `recovered_events` as of the last time they were logged.
+    recovered_logged: u64,
+    /// Samples to go before recovered events can be logged again.
+    recovered_log_countdown: usize,
+}
+
+/// Least time between logs of recovered NaNs and infinities, so a stream of
+/// them doesn't flood the log.
+const RECOVERED_LOG_INTERVAL_SECS: f32 = 5.0;
+
+/// Work `process` hands off to a background thread.
+enum Task {
+    /// `new` NaNs and infinities recovered from since the last log, `total`
+    /// in all.
+    LogRecovered { new: u64, total: u64 },
+}
+
+/// What the sidechain input is doing, for the UI to show.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SidechainStatus {
+    Active,
+    /// Connected but silent, so the sidechain fallback is in use.
+    Silent,
+    /// Not connected, or hasn't carried any signal since the plugin was
+    /// activated, so the sidechain fallback is in use.
+    Missing,
+}
+
+/// A `SidechainStatus` that can be shared with the UI.
+#[derive(Default)]
+pub struct AtomicSidechainStatus(AtomicU8);
+impl AtomicSidechainStatus {
+    pub fn load(&self) -> SidechainStatus {
+        match self.0.load(Ordering::Relaxed) {
+            0 => SidechainStatus::Active,
+            1 => SidechainStatus::Silent,
+            _ => SidechainStatus::Missing,
+        }
+    }
+    pub fn store(&self, status: SidechainStatus) {
+        self.0.store(status as u8, Ordering::Relaxed);
+    }
+}
+
+/// Per-sample parameter values for one block, allocated for the largest
+/// block in `initialize` so `process` doesn't have to.
+#[derive(Default)]
+struct ParamBlocks {
+    morph_k: Vec<f32>,
+    morph_k_side: Vec<f32>,
+    fade_k: Vec<f32>,
+    aux_spectral_spread: Vec<f32>,
+    iter_count: Vec<i32>,
+    gain: Vec<f32>,
+    trim_a: Vec<f32>,
+    trim_b: Vec<f32>,
+}
+impl ParamBlocks {
+    fn resize(&mut self, max_block_len: usize) {
+        util::refill(&mut self.morph_k, max_block_len, 0.0);
+        util::refill(&mut self.morph_k_side, max_block_len, 0.0);
+        util::refill(&mut self.fade_k, max_block_len, 0.0);
+        util::refill(&mut self.aux_spectral_spread, max_block_len, 0.0);
+        util::refill(&mut self.iter_count, max_block_len, 0);
+        util::refill(&mut self.gain, max_block_len, 0.0);
+        util::refill(&mut self.trim_a, max_block_len, 0.0);
+        util::refill(&mut self.trim_b, max_block_len, 0.0);
+    }
+}
+
+impl MorphPlugin {
+    /// Get ready to process up to `max_block_len` samples at a time, on
+    /// `n_channels` main channels.
+    fn setup(&mut self, n_channels: usize, max_block_len: usize, sample_rate: f32) {
+        self.sample_rate = sample_rate;
+        self.param_blocks.resize(max_block_len);
+
+        // start over with fresh processors, one per channel, configured for
+        // this sample rate
+        self.processors = (0..n_channels).map(|_| Processor::new()).collect();
+        self.sidechain = vec![vec![0.0; max_block_len]; n_channels];
+        util::refill(&mut self.link.0, max_block_len, 0.0);
+        util::refill(&mut self.link.1, max_block_len, 0.0);
+        self.update_analysis_config();
+        // with nothing to crossfade from, the configuration goes in right away
+        for processor in self.processors.iter_mut() {
+            processor.reset();
+        }
+        self.stereo_mode = self.requested_stereo_mode(n_channels);
+
+        // the host only connects and disconnects the sidechain while the
+        // plugin is deactivated
+        self.sidechain_seen = false;
+        self.sidechain_status.store(SidechainStatus::Missing);
+
+        // the fresh processors count from zero
+        self.recovered_events.store(0, Ordering::Relaxed);
+        self.recovered_logged = 0;
+        self.recovered_log_countdown = 0;
+    }
+
+    /// What to log about recovered NaNs and infinities after a block of
+    /// `block_len` samples, if anything: the first as soon as they turn up,
+    /// then what's come since at most every `RECOVERED_LOG_INTERVAL_SECS`.
+    fn recovered_events_task(&mut self, block_len: usize) -> Option<Task> {
+        self.recovered_log_countdown = self.recovered_log_countdown.saturating_sub(block_len);
+        let total = self.recovered_events.load(Ordering::Relaxed);
+        if total == self.recovered_logged || self.recovered_log_countdown > 0 {
+            return None;
+        }
+        let new = total - self.recovered_logged;
+        self.recovered_logged = total;
+        self.recovered_log_countdown = (RECOVERED_LOG_INTERVAL_SECS * self.sample_rate) as usize;
+        Some(Task::LogRecovered { new, total })
+    }
+
+    /// Stereo modes only mean anything with two channels.
+    fn requested_stereo_mode(&self, n_channels: usize) -> StereoMode {
+        match n_channels {
+            2 => self.params.stereo_mode.value(),
+            _ => StereoMode::Independent,
+        }
+    }
+
+    fn latency_samples(&self) -> u32 {
+        self.processors[0].latency_samples() as u32
+    }
+
+    /// Reconfigure the processors if the window size, overlap or window
+    /// shape parameters have changed since the last call.
+    fn update_analysis_config(&mut self) {
+        let window_size = match self.params.window_units.value() {
+            WindowUnits::Samples => self.params.window_size.value().samples(),
+            WindowUnits::Milliseconds => {
+                morpher::window_size_for_ms(self.params.window_ms.value(), self.sample_rate)
+            }
+        };
+        let config = AnalysisConfig {
+            window_size,
+            hop_length: window_size / self.params.overlap.value().factor(),
+            window_function: self
+                .params
+                .window_shape
+                .value()
+                .function(self.params.kaiser_beta.value()),
+        };
+        if self.processors[0].config() != config {
+            for processor in self.processors.iter_mut() {
+                processor.configure(config);
+            }
+        }
+    }
+
+    /// Everything `process` does but talk to the host: morph a block of the
+    /// main channels in place, with the sidechain if there is one, writing
+    /// the B -> A branch to `aux_output`.
+    fn process_block(
+        &mut self,
+        samples_main: &mut [&mut [f32]],
+        samples_aux: Option<&[&mut [f32]]>,
+        aux_output: Option<&mut [&mut [f32]]>,
+    ) {
+        self.update_analysis_config();
+
+        let block_len = samples_main.first().map_or(0, |main| main.len());
+
+        // switching stereo modes leaves the processors' state meaningless, so
+        // they carry on in the old mode until they've faded out, then start
+        // over in the new one
+        let stereo_mode = self.requested_stereo_mode(samples_main.len());
+        if stereo_mode != self.stereo_mode {
+            for processor in self.processors.iter_mut() {
+                processor.fade_out();
+            }
+        }
+        if self.processors.iter().all(Processor::faded_out) {
+            self.stereo_mode = stereo_mode;
+            for processor in self.processors.iter_mut() {
+                processor.restart();
+            }
+        }
+        let stereo_mode = self.stereo_mode;
+
+        let blocks = &mut self.param_blocks;
+        let morph_k = &mut blocks.morph_k[..block_len];
+        let morph_k_side = &mut blocks.morph_k_side[..block_len];
+        let fade_k = &mut blocks.fade_k[..block_len];
+        let aux_spectral_spread = &mut blocks.aux_spectral_spread[..block_len];
+        let iter_count = &mut blocks.iter_count[..block_len];
+        let gain = &mut blocks.gain[..block_len];
+        let trim_a = &mut blocks.trim_a[..block_len];
+        let trim_b = &mut blocks.trim_b[..block_len];
+        self.params.k_morph.smoothed.next_block(morph_k, block_len);
+        self.params.k_morph_side.smoothed.next_block(morph_k_side, block_len);
+        self.params.k_fade.smoothed.next_block(fade_k, block_len);
+        self.params.z.smoothed.next_block(aux_spectral_spread, block_len);
+        self.params.iter_count.smoothed.next_block(iter_count, block_len);
+        self.params.gain.smoothed.next_block(gain, block_len);
+        self.params.trim_a.smoothed.next_block(trim_a, block_len);
+        self.params.trim_b.smoothed.next_block(trim_b, block_len);
+        // smoothed in dB, applied as linear gain
+        for db in gain.iter_mut().chain(trim_a.iter_mut()).chain(trim_b.iter_mut()) {
+            *db = nih_plug::util::db_to_gain(*db);
+        }
+        if self.params.branch_output.value() == BranchOutput::Split {
+            fade_k.fill(0.0);
+        }
+
+        let settings = MorphSettings {
+            aux_spectral_spread: aux_spectral_spread[0],
+            iter_count: iter_count[0],
+            combine: self.params.combine.value().mode(&self.params, self.sample_rate),
+            magnitude_law: self
+                .params
+                .magnitude_blend
+                .value()
+                .law(self.params.magnitude_curve.value()),
+            phase_locking: self.params.phase_locking.value(),
+            onset_sensitivity: self.params.onset_sensitivity.value(),
+            reset_scope: self.params.reset_scope.value(),
+            sidechain_fallback: self.params.sidechain_fallback.value(),
+            sidechain_hold: (self.params.sidechain_hold.value() * 0.001 * self.sample_rate)
+                as usize,
+        };
+
+        routing::mix_sidechain(samples_aux, &mut self.sidechain, block_len);
+        for (main, sidechain) in samples_main.iter_mut().zip(self.sidechain.iter_mut()) {
+            apply_gain(main, trim_a);
+            apply_gain(&mut sidechain[..block_len], trim_b);
+        }
+        match stereo_mode {
+            StereoMode::Independent => {}
+            StereoMode::MidSide => {
+                if let [left, right] = &mut samples_main[..] {
+                    routing::to_mid_side(left, right);
+                }
+                if let [left, right] = &mut self.sidechain[..] {
+                    routing::to_mid_side(&mut left[..block_len], &mut right[..block_len]);
+                }
+            }
+            StereoMode::Linked => {
+                routing::mix_down(samples_main, &mut self.link.0[..block_len]);
+                routing::mix_down(&self.sidechain, &mut self.link.1[..block_len]);
+            }
+        }
+
+        let link = (stereo_mode == StereoMode::Linked)
+            .then(|| (&self.link.0[..block_len], &self.link.1[..block_len]));
+        for (channel_id, ((main, sidechain), processor)) in samples_main
+            .iter_mut()
+            .zip(self.sidechain.iter_mut())
+            .zip(self.processors.iter_mut())
+            .enumerate()
+        {
+            let morph_k = match (stereo_mode, channel_id) {
+                (StereoMode::MidSide, 1) => &*morph_k_side,
+                _ => &*morph_k,
+            };
+            processor.process_linked(
+                main,
+                &mut sidechain[..block_len],
+                link,
+                morph_k,
+                fade_k,
+                &settings,
+            );
+        }
+
+        if stereo_mode == StereoMode::MidSide {
+            if let [mid, side] = &mut samples_main[..] {
+                routing::from_mid_side(mid, side);
+            }
+            if let [mid, side] = &mut self.sidechain[..] {
+                routing::from_mid_side(&mut mid[..block_len], &mut side[..block_len]);
+            }
+        }
+        for (main, b_to_a) in samples_main.iter_mut().zip(self.sidechain.iter_mut()) {
+            apply_gain(main, gain);
+            apply_gain(&mut b_to_a[..block_len], gain);
+        }
+        if let Some(aux_output) = aux_output {
+            for (out, b_to_a) in aux_output.iter_mut().zip(self.sidechain.iter()) {
+                out.copy_from_slice(&b_to_a[..block_len]);
+            }
+        }
+
+        //// nih-plug doesn't tell the plugin which buses the host has
+        //// activated, and hands a disconnected one in as silence, so a
+        //// sidechain that's never carried anything counts as missing
+        let samples_aux = samples_aux.filter(|aux| !aux.is_empty());
+        self.sidechain_seen |= samples_aux.is_some_and(|aux| {
+            aux.iter()
+                .any(|channel| channel.iter().any(|sample| *sample != 0.0))
+        });
+        self.sidechain_status.store(if !self.sidechain_seen {
+            SidechainStatus::Missing
+        } else if self.processors.iter().all(Processor::sidechain_silent) {
+            SidechainStatus::Silent
+        } else {
+            SidechainStatus::Active
+        });
+
+        let recovered_events = self.processors.iter().map(Processor::recovered_events).sum();
+        self.recovered_events.store(recovered_events, Ordering::Relaxed);
+    }
 }
-impl MorphPlugin {}
 impl Default for MorphPlugin {
     fn default() -> Self {
         Self {
             params: Arc::new(MorphParams::default()),
             sample_rate: 1.0,
-            processors: [Processor::new(), Processor::new()],
+            reported_latency: 0,
+            processors: vec![Processor::new(), Processor::new()],
+            param_blocks: ParamBlocks::default(),
+            sidechain: Vec::new(),
+            link: (Vec::new(), Vec::new()),
+            stereo_mode: StereoMode::Independent,
+            sidechain_status: Arc::new(AtomicSidechainStatus::default()),
+            sidechain_seen: false,
+            recovered_events: Arc::new(AtomicU64::new(0)),
+            recovered_logged: 0,
+            recovered_log_countdown: 0,
         }
     }
 }
@@ -29,16 +373,244 @@ impl Default for MorphPlugin {
 struct MorphParams {
     #[id = "morph"]
     pub k_morph: FloatParam,
+    #[id = "morph_side"]
+    pub k_morph_side: FloatParam,
     #[id = "fade"]
     pub k_fade: FloatParam,
     #[id = "z"]
     pub z: FloatParam,
     #[id = "iter_count"]
     pub iter_count: IntParam,
+    #[id = "combine"]
+    pub combine: EnumParam<Combine>,
+    #[id = "timbre_morph"]
+    pub timbre_morph: FloatParam,
+    #[id = "excitation_morph"]
+    pub excitation_morph: FloatParam,
+    #[id = "lifter_order"]
+    pub lifter_order: IntParam,
+    #[id = "vocoder_bands"]
+    pub vocoder_bands: IntParam,
+    #[id = "vocoder_low"]
+    pub vocoder_low: FloatParam,
+    #[id = "vocoder_high"]
+    pub vocoder_high: FloatParam,
+    #[id = "vocoder_attack"]
+    pub vocoder_attack: FloatParam,
+    #[id = "vocoder_release"]
+    pub vocoder_release: FloatParam,
+    #[id = "formant_shift"]
+    pub formant_shift: FloatParam,
+    #[id = "vocoder_noise"]
+    pub vocoder_noise: FloatParam,
+    #[id = "whitening"]
+    pub whitening: FloatParam,
+    #[id = "convolution_normalize"]
+    pub convolution_normalize: BoolParam,
+    #[id = "magnitude_law"]
+    pub magnitude_blend: EnumParam<MagnitudeBlend>,
+    #[id = "magnitude_curve"]
+    pub magnitude_curve: FloatParam,
+    #[id = "phase_lock"]
+    pub phase_locking: EnumParam<PhaseLocking>,
+    #[id = "onset_sensitivity"]
+    pub onset_sensitivity: FloatParam,
+    #[id = "reset_scope"]
+    pub reset_scope: EnumParam<ResetScope>,
+    #[id = "sidechain_fallback"]
+    pub sidechain_fallback: EnumParam<SidechainFallback>,
+    #[id = "sidechain_hold"]
+    pub sidechain_hold: FloatParam,
+    #[id = "stereo_mode"]
+    pub stereo_mode: EnumParam<StereoMode>,
+    #[id = "branch_output"]
+    pub branch_output: EnumParam<BranchOutput>,
     // #[id = "2x-mode"]
     // pub double_mode: BoolParam,
     #[id = "gain"]
     pub gain: FloatParam,
+    #[id = "trim_a"]
+    pub trim_a: FloatParam,
+    #[id = "trim_b"]
+    pub trim_b: FloatParam,
+    #[id = "window_units"]
+    pub window_units: EnumParam<WindowUnits>,
+    #[id = "window_size"]
+    pub window_size: EnumParam<WindowSize>,
+    #[id = "window_ms"]
+    pub window_ms: FloatParam,
+    #[id = "overlap"]
+    pub overlap: EnumParam<Overlap>,
+    #[id = "window"]
+    pub window_shape: EnumParam<WindowShape>,
+    #[id = "kaiser_beta"]
+    pub kaiser_beta: FloatParam,
+}
+
+#[derive(Enum, Debug, PartialEq, Eq, Clone, Copy)]
+enum Combine {
+    Blend,
+    #[name = "Cross-Synthesis"]
+    CrossSynthesis,
+    Vocoder,
+    Convolution,
+}
+impl Combine {
+    fn mode(self, params: &MorphParams, sample_rate: f32) -> CombineMode {
+        let samples = |ms: f32| ms * 0.001 * sample_rate;
+        match self {
+            Self::Blend => CombineMode::Blend,
+            Self::CrossSynthesis => CombineMode::CrossSynthesis {
+                timbre: params.timbre_morph.value(),
+                excitation: params.excitation_morph.value(),
+                lifter_order: params.lifter_order.value() as usize,
+            },
+            Self::Vocoder => CombineMode::Vocoder(VocoderSettings {
+                bands: params.vocoder_bands.value() as usize,
+                freq_range: (
+                    params.vocoder_low.value() / sample_rate,
+                    params.vocoder_high.value() / sample_rate,
+                ),
+                attack: samples(params.vocoder_attack.value()),
+                release: samples(params.vocoder_release.value()),
+                formant_shift: params.formant_shift.value() / 12.0,
+                noise: params.vocoder_noise.value(),
+            }),
+            Self::Convolution => CombineMode::Convolution {
+                whitening: params.whitening.value(),
+                normalize: params.convolution_normalize.value(),
+            },
+        }
+    }
+}
+
+#[derive(Enum, Debug, PartialEq, Eq, Clone, Copy)]
+enum MagnitudeBlend {
+    #[name = "Linear Amplitude"]
+    LinearAmplitude,
+    #[name = "Linear Power"]
+    LinearPower,
+    Geometric,
+    #[name = "dB"]
+    Decibel,
+    Curve,
+}
+impl MagnitudeBlend {
+    fn law(self, curve: f32) -> MagnitudeLaw {
+        match self {
+            Self::LinearAmplitude => MagnitudeLaw::LinearAmplitude,
+            Self::LinearPower => MagnitudeLaw::LinearPower,
+            Self::Geometric => MagnitudeLaw::Geometric,
+            Self::Decibel => MagnitudeLaw::Decibel,
+            Self::Curve => MagnitudeLaw::Curve { shape: curve },
+        }
+    }
+}
+
+/// How the two channels of a stereo main bus are morphed.
+#[derive(Enum, Debug, PartialEq, Eq, Clone, Copy)]
+enum StereoMode {
+    /// Left and right each on their own.
+    #[name = "Independent L/R"]
+    Independent,
+    /// Mid and side each on their own, the side with its own morph amount.
+    #[name = "Mid/Side"]
+    MidSide,
+    /// Left and right reset their phases together, on onsets in the mix of
+    /// both, so the stereo image holds still.
+    Linked,
+}
+
+/// What goes to the main output, next to the B -> A branch on the auxiliary
+/// output.
+#[derive(Enum, Debug, PartialEq, Eq, Clone, Copy)]
+enum BranchOutput {
+    /// Both branches crossfaded by X-Fade.
+    #[name = "Faded"]
+    Faded,
+    /// The A -> B branch on its own, so each branch has an output to itself.
+    #[name = "A -> B Only"]
+    Split,
+}
+
+/// Whether the window size is set in samples, or in milliseconds so it
+/// sounds the same at any sample rate.
+#[derive(Enum, Debug, PartialEq, Eq, Clone, Copy)]
+enum WindowUnits {
+    Samples,
+    Milliseconds,
+}
+
+#[derive(Enum, Debug, PartialEq, Eq, Clone, Copy)]
+enum WindowSize {
+    #[name = "256"]
+    W256,
+    #[name = "512"]
+    W512,
+    #[name = "1024"]
+    W1024,
+    #[name = "2048"]
+    W2048,
+    #[name = "4096"]
+    W4096,
+    #[name = "8192"]
+    W8192,
+    #[name = "16384"]
+    W16384,
+}
+impl WindowSize {
+    fn samples(self) -> usize {
+        morpher::MIN_WINDOW_SIZE << self as usize
+    }
+}
+
+#[derive(Enum, Debug, PartialEq, Eq, Clone, Copy)]
+enum Overlap {
+    #[name = "2x"]
+    X2,
+    #[name = "4x"]
+    X4,
+    #[name = "8x"]
+    X8,
+    #[name = "16x"]
+    X16,
+}
+impl Overlap {
+    fn factor(self) -> usize {
+        2 << self as usize
+    }
+}
+
+#[derive(Enum, Debug, PartialEq, Eq, Clone, Copy)]
+enum WindowShape {
+    Hann,
+    #[name = "Hann (Symmetric)"]
+    HannSymmetric,
+    Hamming,
+    Blackman,
+    #[name = "Blackman-Harris"]
+    BlackmanHarris,
+    Kaiser,
+    Gaussian,
+    #[name = "Flat-Top"]
+    FlatTop,
+    #[name = "Sqrt-Hann"]
+    SqrtHann,
+}
+impl WindowShape {
+    fn function(self, kaiser_beta: f32) -> WindowFunction {
+        match self {
+            Self::Hann => WindowFunction::Hann,
+            Self::HannSymmetric => WindowFunction::HannSymmetric,
+            Self::Hamming => WindowFunction::Hamming,
+            Self::Blackman => WindowFunction::Blackman,
+            Self::BlackmanHarris => WindowFunction::BlackmanHarris,
+            Self::Kaiser => WindowFunction::Kaiser { beta: kaiser_beta },
+            Self::Gaussian => WindowFunction::Gaussian { sigma: 0.4 },
+            Self::FlatTop => WindowFunction::FlatTop,
+            Self::SqrtHann => WindowFunction::SqrtHann,
+        }
+    }
 }
 impl Default for MorphParams {
     fn default() -> Self {
@@ -53,6 +625,16 @@ impl Default for MorphParams {
             )
             .with_smoother(SmoothingStyle::Linear(3.0))
             .with_step_size(0.01),
+            k_morph_side: FloatParam::new(
+                "Side Morph",
+                0.0,
+                FloatRange::Linear {
+                    min: 0.0,
+                    max: 1.0,
+                },
+            )
+            .with_smoother(SmoothingStyle::Linear(3.0))
+            .with_step_size(0.01),
             k_fade: FloatParam::new(
                 "X-Fade",
                 0.0,
@@ -82,6 +664,158 @@ impl Default for MorphParams {
                 },
             )
             .with_smoother(SmoothingStyle::None),
+            combine: EnumParam::new("Combine Mode", Combine::Blend),
+            // at full morph, B's formants on A's pitch
+            timbre_morph: FloatParam::new(
+                "Timbre Morph",
+                1.0,
+                FloatRange::Linear {
+                    min: 0.0,
+                    max: 1.0,
+                },
+            )
+            .with_step_size(0.01),
+            excitation_morph: FloatParam::new(
+                "Pitch Morph",
+                0.0,
+                FloatRange::Linear {
+                    min: 0.0,
+                    max: 1.0,
+                },
+            )
+            .with_step_size(0.01),
+            // in samples, below the pitch period of what's being morphed
+            lifter_order: IntParam::new(
+                "Envelope Order",
+                30,
+                IntRange::Linear {
+                    min: 4,
+                    max: 120,
+                },
+            ),
+            vocoder_bands: IntParam::new(
+                "Vocoder Bands",
+                16,
+                IntRange::Linear {
+                    min: 4,
+                    max: morpher::MAX_VOCODER_BANDS as i32,
+                },
+            ),
+            vocoder_low: FloatParam::new(
+                "Vocoder Low",
+                80.0,
+                FloatRange::Skewed {
+                    min: 20.0,
+                    max: 2000.0,
+                    factor: FloatRange::skew_factor(-2.0),
+                },
+            )
+            .with_step_size(1.0)
+            .with_unit(" Hz"),
+            vocoder_high: FloatParam::new(
+                "Vocoder High",
+                12000.0,
+                FloatRange::Skewed {
+                    min: 1000.0,
+                    max: 20000.0,
+                    factor: FloatRange::skew_factor(-1.0),
+                },
+            )
+            .with_step_size(1.0)
+            .with_unit(" Hz"),
+            vocoder_attack: FloatParam::new(
+                "Vocoder Attack",
+                5.0,
+                FloatRange::Skewed {
+                    min: 0.0,
+                    max: 200.0,
+                    factor: FloatRange::skew_factor(-2.0),
+                },
+            )
+            .with_step_size(0.1)
+            .with_unit(" ms"),
+            vocoder_release: FloatParam::new(
+                "Vocoder Release",
+                50.0,
+                FloatRange::Skewed {
+                    min: 0.0,
+                    max: 1000.0,
+                    factor: FloatRange::skew_factor(-2.0),
+                },
+            )
+            .with_step_size(0.1)
+            .with_unit(" ms"),
+            formant_shift: FloatParam::new(
+                "Formant Shift",
+                0.0,
+                FloatRange::Linear {
+                    min: -12.0,
+                    max: 12.0,
+                },
+            )
+            .with_step_size(0.1)
+            .with_unit(" st"),
+            // how much noise fills in for consonants the carrier can't make
+            vocoder_noise: FloatParam::new(
+                "Unvoiced Noise",
+                0.3,
+                FloatRange::Linear {
+                    min: 0.0,
+                    max: 1.0,
+                },
+            )
+            .with_step_size(0.01),
+            // how much of the filtering input's overall tone is flattened out
+            whitening: FloatParam::new(
+                "Whitening",
+                0.5,
+                FloatRange::Linear {
+                    min: 0.0,
+                    max: 1.0,
+                },
+            )
+            .with_step_size(0.01),
+            convolution_normalize: BoolParam::new("Normalize Convolution", true),
+            magnitude_blend: EnumParam::new("Magnitude Law", MagnitudeBlend::Geometric),
+            magnitude_curve: FloatParam::new(
+                "Magnitude Curve",
+                0.0,
+                FloatRange::Linear {
+                    min: -1.0,
+                    max: 1.0,
+                },
+            )
+            .with_step_size(0.01),
+            phase_locking: EnumParam::new("Phase Locking", PhaseLocking::Off),
+            onset_sensitivity: FloatParam::new(
+                "Onset Sensitivity",
+                0.5,
+                FloatRange::Linear {
+                    min: 0.0,
+                    max: 1.0,
+                },
+            )
+            .with_step_size(0.01),
+            reset_scope: EnumParam::new("Onset Reset", ResetScope::PerBin),
+            sidechain_fallback: EnumParam::new(
+                "Sidechain Fallback",
+                SidechainFallback::PassThroughA,
+            ),
+            // how long the sidechain has to stay silent before the fallback
+            // takes over, so pauses in it are left alone
+            sidechain_hold: FloatParam::new(
+                "Sidechain Hold",
+                100.0,
+                FloatRange::Skewed {
+                    min: 100.0,
+                    max: 2000.0,
+                    factor: FloatRange::skew_factor(-1.0),
+                },
+            )
+            .with_step_size(1.0)
+            .with_unit(" ms"),
+            stereo_mode: EnumParam::new("Stereo Mode", StereoMode::Independent),
+            branch_output: EnumParam::new("Main Output", BranchOutput::Faded),
             gain: FloatParam::new(
                 "Gain",
                 -10.0,
@@ -93,11 +827,68 @@ impl Default for MorphParams {
             .with_smoother(SmoothingStyle::Linear(3.0))
             .with_step_size(0.01)
             .with_unit(" dB"),
+            trim_a: FloatParam::new(
+                "Trim A",
+                0.0,
+                FloatRange::Linear {
+                    min: -24.0,
+                    max: 24.0,
+                },
+            )
+            .with_smoother(SmoothingStyle::Linear(3.0))
+            .with_step_size(0.01)
+            .with_unit(" dB"),
+            trim_b: FloatParam::new(
+                "Trim B",
+                0.0,
+                FloatRange::Linear {
+                    min: -24.0,
+                    max: 24.0,
+                },
+            )
+            .with_smoother(SmoothingStyle::Linear(3.0))
+            .with_step_size(0.01)
+            .with_unit(" dB"),
+            window_units: EnumParam::new("Window Units", WindowUnits::Samples),
+            window_size: EnumParam::new("Window Size", WindowSize::W1024),
+            // rounded to the nearest window size in samples
+            window_ms: FloatParam::new(
+                "Window Length",
+                23.0,
+                FloatRange::Skewed {
+                    min: 5.0,
+                    max: 400.0,
+                    factor: FloatRange::skew_factor(-2.0),
+                },
+            )
+            .with_step_size(0.1)
+            .with_unit(" ms"),
+            overlap: EnumParam::new("Overlap", Overlap::X4),
+            window_shape: EnumParam::new("Window", WindowShape::Hann),
+            kaiser_beta: FloatParam::new(
+                "Kaiser Beta",
+                8.0,
+                FloatRange::Linear {
+                    min: 0.0,
+                    max: 20.0,
+                },
+            )
+            .with_step_size(0.1),
         }
     }
 }
 
-const N_CHANNELS: usize = 2;
+const PORT_NAMES: PortNames = PortNames {
+    aux_inputs: &["Sidechain (B)"],
+    aux_outputs: &["B -> A"],
+    ..PortNames::const_default()
+};
+
+fn apply_gain(samples: &mut [f32], gain: &[f32]) {
+    for (sample, gain) in samples.iter_mut().zip(gain) {
+        *sample *= gain;
+    }
+}
 
 impl Plugin for MorphPlugin {
     const NAME: &'static str = "SR-FFTMorph";
@@ -110,64 +901,105 @@ impl Plugin for MorphPlugin {
 
     const VERSION: &'static str = "0.0.0";
 
-    const AUDIO_IO_LAYOUTS: &'static [AudioIOLayout] = &[AudioIOLayout {
-        main_input_channels: NonZeroU32::new(N_CHANNELS as u32),
-        main_output_channels: NonZeroU32::new(N_CHANNELS as u32),
-        aux_input_ports: &[new_nonzero_u32(N_CHANNELS as u32)],
-        ..AudioIOLayout::const_default()
-    }];
+    const AUDIO_IO_LAYOUTS: &'static [AudioIOLayout] = &[
+        AudioIOLayout {
+            main_input_channels: NonZeroU32::new(2),
+            main_output_channels: NonZeroU32::new(2),
+            aux_input_ports: &[new_nonzero_u32(2)],
+            aux_output_ports: &[new_nonzero_u32(2)],
+            names: PORT_NAMES,
+            ..AudioIOLayout::const_default()
+        },
+        AudioIOLayout {
+            main_input_channels: NonZeroU32::new(1),
+            main_output_channels: NonZeroU32::new(1),
+            aux_input_ports: &[new_nonzero_u32(1)],
+            aux_output_ports: &[new_nonzero_u32(1)],
+            names: PORT_NAMES,
+            ..AudioIOLayout::const_default()
+        },
+        // B goes to both channels
+        AudioIOLayout {
+            main_input_channels: NonZeroU32::new(2),
+            main_output_channels: NonZeroU32::new(2),
+            aux_input_ports: &[new_nonzero_u32(1)],
+            aux_output_ports: &[new_nonzero_u32(2)],
+            names: PORT_NAMES,
+            ..AudioIOLayout::const_default()
+        },
+        // B is mixed down to mono
+        AudioIOLayout {
+            main_input_channels: NonZeroU32::new(1),
+            main_output_channels: NonZeroU32::new(1),
+            aux_input_ports: &[new_nonzero_u32(2)],
+            aux_output_ports: &[new_nonzero_u32(1)],
+            names: PORT_NAMES,
+            ..AudioIOLayout::const_default()
+        },
+    ];
 
     const MIDI_INPUT: MidiConfig = MidiConfig::None;
     const MIDI_OUTPUT: MidiConfig = MidiConfig::None;
 
     type SysExMessage = ();
-    type BackgroundTask = ();
+    type BackgroundTask = Task;
 
     fn initialize(
         &mut self,
-        _audio_io_layout: &AudioIOLayout,
+        audio_io_layout: &AudioIOLayout,
         buffer_config: &BufferConfig,
-        _context: &mut impl InitContext<Self>,
+        context: &mut impl InitContext<Self>,
     ) -> bool {
-        self.sample_rate = buffer_config.sample_rate;
+        let n_channels = audio_io_layout
+            .main_output_channels
+            .map_or(0, NonZeroU32::get) as usize;
+        self.setup(
+            n_channels,
+            buffer_config.max_buffer_size as usize,
+            buffer_config.sample_rate,
+        );
+        self.reported_latency = self.latency_samples();
+        context.set_latency_samples(self.reported_latency);
 
         true
     }
 
-    fn reset(&mut self) {}
+    fn reset(&mut self) {
+        for processor in self.processors.iter_mut() {
+            processor.reset();
+        }
+    }
 
     fn params(&self) -> std::sync::Arc<dyn Params> {
         self.params.clone()
     }
 
+    fn task_executor(&mut self) -> TaskExecutor<Self> {
+        // formatting a log message allocates, so it's done off the audio thread
+        Box::new(|task| match task {
+            Task::LogRecovered { new, total } => {
+                nih_log!("Recovered from {new} NaN or infinite values, {total} in total")
+            }
+        })
+    }
+
     fn process(
         &mut self,
         buffer: &mut Buffer,
         aux: &mut AuxiliaryBuffers,
-        _context: &mut impl ProcessContext<Self>,
+        context: &mut impl ProcessContext<Self>,
     ) -> ProcessStatus {
-        let block_len = buffer.samples();
-        let samples_main = buffer.as_slice();
-        let samples_aux = aux.inputs[0].as_slice();
-        
-        let mut morph_k = vec![0.0; block_len];
-        let mut fade_k = vec![0.0; block_len];
-        let mut aux_spectral_spread = vec![0.0; block_len];
-        let mut iter_count = vec![0; block_len];
-        self.params.k_morph.smoothed.next_block(&mut morph_k[..], block_len);
-        self.params.k_fade.smoothed.next_block(&mut fade_k[..], block_len);
-        self.params.z.smoothed.next_block(&mut aux_spectral_spread[..], block_len);
-        self.params.iter_count.smoothed.next_block(&mut iter_count[..], block_len);
-
-        for channel_id in 0..N_CHANNELS {
-            self.processors[channel_id].process(
-                samples_main[channel_id],
-                samples_aux[channel_id],
-                &morph_k,
-                &fade_k,
-                aux_spectral_spread[0],
-                iter_count[0],
-            );
+        let samples_aux = aux.inputs.first().map(|aux| aux.as_slice_immutable());
+        let aux_output = aux.outputs.first_mut().map(|aux| aux.as_slice());
+        self.process_block(buffer.as_slice(), samples_aux, aux_output);
+        if let Some(task) = self.recovered_events_task(buffer.samples()) {
+            context.execute_background(task);
+        }
+
+        let latency_samples = self.latency_samples();
+        if latency_samples != self.reported_latency {
+            self.reported_latency = latency_samples;
+            context.set_latency_samples(latency_samples);
         }
 
         ProcessStatus::Normal
@@ -196,3 +1028,130 @@ impl Vst3Plugin for MorphPlugin {
 
 nih_export_clap!(MorphPlugin);
 nih_export_vst3!(MorphPlugin);
+
+#[cfg(test)]
+mod test {
+    use super::{MorphPlugin, SidechainStatus};
+
+    /// Only under `cargo test --release`, see `util::allocations`.
+    #[cfg(not(debug_assertions))]
+    #[test]
+    fn plugin_process_does_not_allocate() {
+        use crate::util::allocations::count_allocations;
+
+        // every layout, through smoothing, routing and all the processors
+        const MAX_BLOCK_LEN: usize = 2048;
+        for (n_main, n_aux) in [(2, 2), (1, 1), (2, 1), (1, 2)] {
+            let mut plugin = MorphPlugin::default();
+            plugin.setup(n_main, MAX_BLOCK_LEN, 44100.0);
+            let mut main = vec![vec![0.0; MAX_BLOCK_LEN]; n_main];
+            let mut aux = vec![vec![0.0; MAX_BLOCK_LEN]; n_aux];
+            let mut aux_output = vec![vec![0.0; MAX_BLOCK_LEN]; n_main];
+
+            let mut allocations = 0;
+            let mut pos = 0;
+            for block_len in [441, 1, 37, 2048, 512].into_iter().cycle().take(100) {
+                for (channel_id, channel) in main.iter_mut().chain(aux.iter_mut()).enumerate() {
+                    for (i, sample) in channel[..block_len].iter_mut().enumerate() {
+                        *sample = ((pos + i) as f32 * 0.01 * (channel_id + 1) as f32).sin();
+                    }
+                }
+                pos += block_len;
+
+                // the slices are the host's, so they're made outside the count
+                let mut main: Vec<&mut [f32]> = main
+                    .iter_mut()
+                    .map(|channel| &mut channel[..block_len])
+                    .collect();
+                let aux: Vec<&mut [f32]> = aux
+                    .iter_mut()
+                    .map(|channel| &mut channel[..block_len])
+                    .collect();
+                let mut aux_output: Vec<&mut [f32]> = aux_output
+                    .iter_mut()
+                    .map(|channel| &mut channel[..block_len])
+                    .collect();
+                let (_, block_allocations) = count_allocations(|| {
+                    plugin.process_block(&mut main, Some(&aux), Some(&mut aux_output))
+                });
+                allocations += block_allocations;
+            }
+            assert_eq!(allocations, 0, "{n_main} main, {n_aux} sidechain channels");
+        }
+    }
+
+    #[test]
+    fn plugin_sidechain_status() {
+        const BLOCK_LEN: usize = 512;
+        let mut plugin = MorphPlugin::default();
+        plugin.setup(2, BLOCK_LEN, 44100.0);
+        let status = plugin.sidechain_status.clone();
+        let mut main = vec![vec![0.0; BLOCK_LEN]; 2];
+        // run blocks with the sidechain at `level`, or without one
+        let mut aux = vec![vec![0.0; BLOCK_LEN]; 2];
+        let mut run = |plugin: &mut MorphPlugin, level: Option<f32>, n_blocks: usize| {
+            for block_id in 0..n_blocks {
+                for (main, aux) in main.iter_mut().zip(aux.iter_mut()) {
+                    for (i, (a, b)) in main.iter_mut().zip(aux.iter_mut()).enumerate() {
+                        let phase = (block_id * BLOCK_LEN + i) as f32 * 0.05;
+                        *a = phase.sin();
+                        *b = (phase * 3.0).sin() * level.unwrap_or(0.0);
+                    }
+                }
+                let mut main: Vec<&mut [f32]> =
+                    main.iter_mut().map(|channel| &mut channel[..]).collect();
+                let aux: Vec<&mut [f32]> = aux.iter_mut().map(|channel| &mut channel[..]).collect();
+                plugin.process_block(&mut main, level.map(|_| &aux[..]), None);
+            }
+        };
+
+        // no sidechain, or one that's never carried anything
+        run(&mut plugin, None, 4);
+        assert_eq!(status.load(), SidechainStatus::Missing);
+        run(&mut plugin, Some(0.0), 4);
+        assert_eq!(status.load(), SidechainStatus::Missing);
+        run(&mut plugin, Some(0.5), 4);
+        assert_eq!(status.load(), SidechainStatus::Active);
+        // it's connected, so going quiet is only silence, once held long enough
+        run(&mut plugin, Some(0.0), 1);
+        assert_eq!(status.load(), SidechainStatus::Active);
+        run(&mut plugin, Some(0.0), 40);
+        assert_eq!(status.load(), SidechainStatus::Silent);
+        // until the plugin's activated again
+        plugin.setup(2, BLOCK_LEN, 44100.0);
+        run(&mut plugin, Some(0.0), 4);
+        assert_eq!(status.load(), SidechainStatus::Missing);
+    }
+
+    #[test]
+    fn plugin_recovered_events_logged_at_most_every_interval() {
+        use super::{Task, RECOVERED_LOG_INTERVAL_SECS};
+
+        const BLOCK_LEN: usize = 512;
+        const SAMPLE_RATE: f32 = 2000.0;
+        let mut plugin = MorphPlugin::default();
+        plugin.setup(2, BLOCK_LEN, SAMPLE_RATE);
+        let interval = (RECOVERED_LOG_INTERVAL_SECS * SAMPLE_RATE) as usize;
+
+        // a steady stream of NaNs is logged as it starts, then once an interval
+        let mut main = vec![vec![0.0; BLOCK_LEN]; 2];
+        let mut logs = Vec::new();
+        for _ in 0..3 * interval / BLOCK_LEN {
+            let mut main: Vec<&mut [f32]> = main
+                .iter_mut()
+                .map(|channel| {
+                    channel.fill(f32::NAN);
+                    &mut channel[..]
+                })
+                .collect();
+            plugin.process_block(&mut main, None, None);
+            logs.extend(plugin.recovered_events_task(BLOCK_LEN));
+        }
+        assert_eq!(logs.len(), 3);
+        let Some(Task::LogRecovered { total, .. }) = logs.last() else {
+            unreachable!()
+        };
+        let logged: u64 = logs.iter().map(|Task::LogRecovered { new, .. }| new).sum();
+        assert_eq!(logged, *total);
+    }
+}
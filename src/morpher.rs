@@ -1,102 +1,664 @@
-use rustfft::{
-    num_complex::{Complex32, ComplexFloat},
-    FftPlanner,
+use realfft::{num_complex::Complex32, ComplexToReal, RealFftPlanner, RealToComplex};
+
+use self::{
+    convolution::convolve,
+    envelope::SpectralEnvelope,
+    onset::{onset_threshold, OnsetDetector, PhaseReset},
+    phase_lock::PhaseLocker,
+    sidechain::{Noise, SilenceGate},
+    spread::SpectralSpread,
+    vocoder::Vocoder,
+};
+pub use self::{
+    magnitude::MagnitudeLaw,
+    onset::ResetScope,
+    phase_lock::PhaseLocking,
+    sidechain::SidechainFallback,
+    vocoder::{VocoderSettings, MAX_BANDS as MAX_VOCODER_BANDS},
+};
+use crate::{
+    util::{lerpable::Lerpable, refill, ring_buffer::RingBuffer, sanitize, wrap_phase},
+    window::{fill_synthesis_window, WindowFunction},
 };
 
-use crate::util::{lerpable::Lerpable, ring_buffer::RingBuffer};
+mod convolution;
+mod envelope;
+mod magnitude;
+mod onset;
+mod phase_lock;
+mod sidechain;
+mod spread;
+mod vocoder;
+
+pub const MIN_WINDOW_SIZE: usize = 256;
+pub const MAX_WINDOW_SIZE: usize = 16384;
+/// Bins in the spectrum of the largest window.
+const MAX_BINS: usize = MAX_WINDOW_SIZE / 2 + 1;
+/// Width of the sidechain's spectral blur, in octaves, at full spread.
+const MAX_SPECTRAL_SPREAD_OCTAVES: f32 = 2.0;
+/// Quefrencies kept in the envelopes convolution whitens by, in samples.
+const WHITENING_LIFTER_ORDER: usize = 30;
+
+/// The supported window size closest to `ms` milliseconds at `sample_rate`,
+/// rounding to the nearest power of two in octaves.
+pub fn window_size_for_ms(ms: f32, sample_rate: f32) -> usize {
+    let octaves = (ms * 0.001 * sample_rate).max(1.0).log2().round() as u32;
+    1 << octaves.clamp(
+        MIN_WINDOW_SIZE.trailing_zeros(),
+        MAX_WINDOW_SIZE.trailing_zeros(),
+    )
+}
+
+/// Root mean square of `x`.
+fn rms(x: &[f32]) -> f32 {
+    (x.iter().map(|v| v * v).sum::<f32>() / x.len() as f32).sqrt()
+}
+
+/// Everything that determines the shape of the STFT.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AnalysisConfig {
+    pub window_size: usize,
+    pub hop_length: usize,
+    pub window_function: WindowFunction,
+}
+impl AnalysisConfig {
+    /// Delay through a `Morpher` with this configuration, in samples.
+    ///
+    /// A sample only leaves `output_buf` once every window overlapping it
+    /// has been added in, which takes `window_size - hop_length` samples.
+    pub fn latency_samples(&self) -> usize {
+        self.window_size - self.hop_length
+    }
+}
+impl Default for AnalysisConfig {
+    fn default() -> Self {
+        Self {
+            window_size: 1024,
+            hop_length: 256,
+            window_function: WindowFunction::Hann,
+        }
+    }
+}
+
+/// How the spectra of A and B are combined.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CombineMode {
+    /// Morph the magnitudes of each bin as they are.
+    Blend,
+    /// Split each spectrum into its smooth envelope (the timbre) and the
+    /// fine structure under it (the pitch or excitation), and morph the two
+    /// separately. At full morph, the envelope has gone `timbre` of the way
+    /// over and the fine structure and phases `excitation` of the way, so
+    /// `timbre: 1.0, excitation: 0.0` puts B's formants on A's pitch.
+    CrossSynthesis {
+        timbre: f32,
+        excitation: f32,
+        /// Quefrencies kept in the envelope, in samples.
+        lifter_order: usize,
+    },
+    /// A channel vocoder: A's spectrum with B's band levels in the A -> B
+    /// branch, B's with A's in B -> A. Morphing goes from the dry input to
+    /// the vocoded one.
+    Vocoder(VocoderSettings),
+    /// Multiply the spectra together, convolving A with B, and morph from
+    /// the dry input to that. B is whitened by `whitening` of its spectral
+    /// envelope first (and A, for B -> A), and with `normalize`, the result
+    /// is brought back to the dry input's level. Without it, a full-scale B
+    /// passes A at about its own level, whatever the window size.
+    Convolution { whitening: f32, normalize: bool },
+}
+impl CombineMode {
+    /// How far the phases have morphed at `k_morph`.
+    fn phase_k(self, k_morph: f32) -> f32 {
+        match self {
+            Self::Blend | Self::Convolution { .. } => k_morph,
+            Self::CrossSynthesis { excitation, .. } => k_morph * excitation,
+            //// the carrier's phases
+            Self::Vocoder(_) => 0.0,
+        }
+    }
+
+    /// Whether B only shapes A's sound, as a vocoder's modulator or a
+    /// filter, rather than being morphed into.
+    fn b_shapes_a(self) -> bool {
+        matches!(self, Self::Vocoder(_) | Self::Convolution { .. })
+    }
+}
+
+/// Per-block settings for `Morpher::morph`, other than the sample-accurate
+/// morph and fade amounts.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MorphSettings {
+    pub aux_spectral_spread: f32,
+    pub iter_count: i32,
+    pub combine: CombineMode,
+    pub magnitude_law: MagnitudeLaw,
+    pub phase_locking: PhaseLocking,
+    /// How readily a jump in level resets the phase, `0` turns it off.
+    pub onset_sensitivity: f32,
+    pub reset_scope: ResetScope,
+    /// What to do while B is silent.
+    pub sidechain_fallback: SidechainFallback,
+    /// How long B has to stay silent before the fallback takes over, in
+    /// samples.
+    pub sidechain_hold: usize,
+}
+impl Default for MorphSettings {
+    fn default() -> Self {
+        Self {
+            aux_spectral_spread: 0.0,
+            iter_count: 0,
+            combine: CombineMode::Blend,
+            magnitude_law: MagnitudeLaw::Geometric,
+            phase_locking: PhaseLocking::Off,
+            onset_sensitivity: 0.5,
+            reset_scope: ResetScope::PerBin,
+            sidechain_fallback: SidechainFallback::PassThroughA,
+            //// 100 ms at 48 kHz
+            sidechain_hold: 4800,
+        }
+    }
+}
+
+type FftPlanFwd = std::sync::Arc<dyn RealToComplex<f32>>;
+type FftPlanInv = std::sync::Arc<dyn ComplexToReal<f32>>;
 
 /// Morphs two single-channel audio signals together
 pub struct Morpher {
-    fft_fwd: std::sync::Arc<dyn rustfft::Fft<f32>>,
-    fft_inv: std::sync::Arc<dyn rustfft::Fft<f32>>,
+    /// Forward and inverse plans for every supported window size, so
+    /// switching sizes on the audio thread doesn't have to plan anything.
+    fft_plans: Vec<(FftPlanFwd, FftPlanInv)>,
+    fft_fwd: FftPlanFwd,
+    fft_inv: FftPlanInv,
+    /// Scratch for whichever plan is in use.
+    fft_scratch: Vec<Complex32>,
 
-    window_func: Vec<f32>,
+    window_function: WindowFunction,
+    analysis_window: Vec<f32>,
+    synthesis_window: Vec<f32>,
+    /// `analysis * synthesis` summed over the frames overlapping each sample
+    /// so far, i.e. how much of its final value `output_buf` holds once the
+    /// current frame is added. Only the first hop is complete.
+    partial_window_sum: Vec<f32>,
+    /// The magnitude a full-scale sine peaks at in an unnormalized spectrum,
+    /// and the level of a full-scale signal's spectral envelope, for
+    /// convolution to filter relative to.
+    full_scale: (f32, f32),
     window_size: usize,
     hop_length: usize,
+    /// `window_size / 2 + 1`, the bins a real signal's spectrum is made of.
+    n_bins: usize,
 
     input_buf_a: RingBuffer<f32>,
     input_buf_b: RingBuffer<f32>,
+    /// The signals onsets are detected in when linked to other channels, in
+    /// place of A and B.
+    link_buf_a: RingBuffer<f32>,
+    link_buf_b: RingBuffer<f32>,
+    /// `k_morph` for each sample of `input_buf_a` and `input_buf_b`.
+    k_morph_buf: RingBuffer<f32>,
+    /// Overlap-added output of the (A -> B, B -> A) branches.
     output_buf: RingBuffer<(f32, f32)>,
 
+    /// Time domain frames of both inputs, then of both branches' output.
+    frame_buf: (Vec<f32>, Vec<f32>),
+    /// Both branches' output morphed with the lowest `k_morph` over the
+    /// frame, when it's changed over the frame. `frame_buf` then has the
+    /// highest.
+    frame_buf_low: (Vec<f32>, Vec<f32>),
     proc_buf: (Vec<Complex32>, Vec<Complex32>),
     phase_accum: Vec<(f32, f32)>,
     phase_prev: Vec<(f32, f32)>,
-    // mag_faded: Vec<(f32, f32)>,
-    mag_prev: Vec<(f32, f32)>,
+    mag_a: Vec<f32>,
+    /// B's magnitude spectrum after spreading.
+    mag_b: Vec<f32>,
+    /// B's instantaneous frequencies, to keep holding its spectrum going.
+    inst_freq_b: Vec<f32>,
+    /// The (magnitude, phase) of B's spectrum to hold, from the last frame
+    /// it was sounding, with the phase kept turning since.
+    held_b: Vec<(f32, f32)>,
+    spread: SpectralSpread,
+    envelope: SpectralEnvelope,
+    /// Spectral envelopes of A and B, when cross-synthesizing or convolving.
+    envelopes: (Vec<f32>, Vec<f32>),
+    vocoder: Vocoder,
+    /// Magnitudes the (A -> B, B -> A) branches morph towards, for the
+    /// modes that work them out up front: vocoded or convolved.
+    wet: (Vec<f32>, Vec<f32>),
+    /// Noise to add on top of the vocoded branches.
+    noise_bins: (Vec<Complex32>, Vec<Complex32>),
+    onset_detectors: (OnsetDetector, OnsetDetector),
+    /// Magnitudes of one of the link signals.
+    link_mags: Vec<f32>,
+    /// What happens to each bin's phase in the (A -> B, B -> A) branches
+    /// this hop.
+    phase_resets: Vec<(PhaseReset, PhaseReset)>,
+
+    sidechain_gate: SilenceGate,
+    /// How far the sidechain fallback is faded in, for each sample of the
+    /// last hop.
+    fallback_mix: Vec<f32>,
+    noise: Noise,
+
+    /// Phase locking for the (A -> B, B -> A) branches.
+    phase_lockers: (PhaseLocker, PhaseLocker),
+
+    /// Scratch for iterative phase reconstruction: one branch's part of
+    /// `output_buf`, and the frame and spectrum being refined.
+    committed: Vec<f32>,
+    iter_frame: Vec<f32>,
+    iter_buf: Vec<Complex32>,
+
+    /// Non-finite values found and recovered from so far, in the inputs, the
+    /// morphed bins or the output.
+    recovered_events: u64,
 }
 
-fn cosine_window_fn(window_size: usize) -> Vec<f32> {
-    let mut output = vec![0.0; window_size];
-    for i in 0..window_size {
-        output[i] =
-            1.0 - ((i + 1) as f32 / (window_size + 1) as f32 * std::f32::consts::PI * 2.0).cos();
+impl Default for Morpher {
+    fn default() -> Self {
+        Self::new()
     }
-    output
 }
-
 impl Morpher {
     pub fn new() -> Self {
-        const DEFAULT_WINDOW_SIZE: usize = 1024;
-        const DEFAULT_HOP_LENGTH: usize = 256;
-        let window_size = DEFAULT_WINDOW_SIZE;
-        let hop_length = DEFAULT_HOP_LENGTH;
-        let mut fft_planner = FftPlanner::new();
-        let fft_fwd = fft_planner.plan_fft_forward(window_size);
-        let fft_inv = fft_planner.plan_fft_inverse(window_size);
-        Self {
+        let mut fft_planner = RealFftPlanner::new();
+        let fft_plans: Vec<_> = std::iter::successors(Some(MIN_WINDOW_SIZE), |size| {
+            Some(size * 2).filter(|&size| size <= MAX_WINDOW_SIZE)
+        })
+        .map(|size| {
+            (
+                fft_planner.plan_fft_forward(size),
+                fft_planner.plan_fft_inverse(size),
+            )
+        })
+        .collect();
+        let (fft_fwd, fft_inv) = fft_plans[0].clone();
+        let fft_scratch_len = fft_plans
+            .iter()
+            .map(|(fwd, inv)| fwd.get_scratch_len().max(inv.get_scratch_len()))
+            .max()
+            .unwrap_or(0);
+
+        let mut morpher = Self {
+            fft_plans,
             fft_fwd,
             fft_inv,
+            fft_scratch: vec![Complex32::default(); fft_scratch_len],
 
-            window_size,
-            hop_length,
+            window_size: 0,
+            hop_length: 0,
+            n_bins: 0,
 
-            window_func: cosine_window_fn(window_size),
+            window_function: WindowFunction::Hann,
+            analysis_window: Vec::with_capacity(MAX_WINDOW_SIZE),
+            synthesis_window: Vec::with_capacity(MAX_WINDOW_SIZE),
+            partial_window_sum: Vec::with_capacity(MAX_WINDOW_SIZE),
+            full_scale: (1.0, 1.0),
 
-            input_buf_a: RingBuffer::new(window_size, 0.0),
-            input_buf_b: RingBuffer::new(window_size, 0.0),
-            output_buf: RingBuffer::new(window_size, (0.0, 0.0)),
+            input_buf_a: RingBuffer::with_capacity(MAX_WINDOW_SIZE),
+            input_buf_b: RingBuffer::with_capacity(MAX_WINDOW_SIZE),
+            link_buf_a: RingBuffer::with_capacity(MAX_WINDOW_SIZE),
+            link_buf_b: RingBuffer::with_capacity(MAX_WINDOW_SIZE),
+            output_buf: RingBuffer::with_capacity(MAX_WINDOW_SIZE),
+            k_morph_buf: RingBuffer::with_capacity(MAX_WINDOW_SIZE),
 
-            proc_buf: (
-                vec![Complex32::default(); window_size],
-                vec![Complex32::default(); window_size],
+            frame_buf: (
+                Vec::with_capacity(MAX_WINDOW_SIZE),
+                Vec::with_capacity(MAX_WINDOW_SIZE),
+            ),
+            frame_buf_low: (
+                Vec::with_capacity(MAX_WINDOW_SIZE),
+                Vec::with_capacity(MAX_WINDOW_SIZE),
             ),
+            proc_buf: (Vec::with_capacity(MAX_BINS), Vec::with_capacity(MAX_BINS)),
 
-            phase_accum: vec![(0.0, 0.0); window_size],
-            phase_prev: vec![(0.0, 0.0); window_size],
-            // mag_faded: vec![(0.0, 0.0); window_size],
-            mag_prev: vec![(0.0, 0.0); window_size],
+            phase_accum: Vec::with_capacity(MAX_BINS),
+            phase_prev: Vec::with_capacity(MAX_BINS),
+            mag_a: Vec::with_capacity(MAX_BINS),
+            mag_b: Vec::with_capacity(MAX_BINS),
+            inst_freq_b: Vec::with_capacity(MAX_BINS),
+            held_b: Vec::with_capacity(MAX_BINS),
+            spread: SpectralSpread::new(MAX_BINS),
+            envelope: SpectralEnvelope::new(MAX_WINDOW_SIZE),
+            envelopes: (Vec::with_capacity(MAX_BINS), Vec::with_capacity(MAX_BINS)),
+            vocoder: Vocoder::new(),
+            wet: (Vec::with_capacity(MAX_BINS), Vec::with_capacity(MAX_BINS)),
+            noise_bins: (Vec::with_capacity(MAX_BINS), Vec::with_capacity(MAX_BINS)),
+            onset_detectors: (OnsetDetector::new(MAX_BINS), OnsetDetector::new(MAX_BINS)),
+            link_mags: Vec::with_capacity(MAX_BINS),
+            phase_resets: Vec::with_capacity(MAX_BINS),
+
+            sidechain_gate: SilenceGate::new(),
+            fallback_mix: Vec::with_capacity(MAX_WINDOW_SIZE),
+            noise: Noise::new(),
+
+            phase_lockers: (PhaseLocker::new(MAX_BINS), PhaseLocker::new(MAX_BINS)),
+
+            committed: Vec::with_capacity(MAX_WINDOW_SIZE),
+            iter_frame: Vec::with_capacity(MAX_WINDOW_SIZE),
+            iter_buf: Vec::with_capacity(MAX_BINS),
+
+            recovered_events: 0,
+        };
+        morpher.configure(AnalysisConfig::default());
+        morpher
+    }
+
+    /// Switch to a new STFT configuration. Changing only the window function
+    /// keeps the running state, anything else clears it.
+    ///
+    /// Everything is preallocated for `MAX_WINDOW_SIZE`, so this doesn't
+    /// allocate and is safe to call from the audio thread.
+    pub fn configure(&mut self, config: AnalysisConfig) {
+        let AnalysisConfig {
+            window_size,
+            hop_length,
+            window_function,
+        } = config;
+        assert!(
+            window_size.is_power_of_two()
+                && (MIN_WINDOW_SIZE..=MAX_WINDOW_SIZE).contains(&window_size),
+            "Morpher: Unsupported window size {window_size}."
+        );
+        assert!(
+            hop_length > 0 && window_size.is_multiple_of(hop_length),
+            "Morpher: Hop length {hop_length} doesn't divide window size {window_size}."
+        );
+        let resized = (window_size, hop_length) != (self.window_size, self.hop_length);
+        self.window_size = window_size;
+        self.hop_length = hop_length;
+        self.n_bins = window_size / 2 + 1;
+        self.window_function = window_function;
+
+        refill(&mut self.analysis_window, window_size, 0.0);
+        refill(&mut self.synthesis_window, window_size, 0.0);
+        window_function.fill(&mut self.analysis_window);
+        fill_synthesis_window(
+            &self.analysis_window,
+            hop_length,
+            &mut self.synthesis_window,
+        );
+        refill(&mut self.partial_window_sum, window_size, 0.0);
+        for i in 0..window_size {
+            self.partial_window_sum[i] = (i..window_size)
+                .step_by(hop_length)
+                .map(|j| self.analysis_window[j] * self.synthesis_window[j])
+                .sum();
+        }
+        // full_scale = (sum(window_fn) / 2, sqrt(sum(window_fn^2) / 2))
+        //// the envelope follows the spectrum's power across the bins rather than its peaks,
+        //// which for a full-scale signal comes to the window's power
+        self.full_scale = (
+            self.analysis_window.iter().sum::<f32>() * 0.5,
+            (self.analysis_window.iter().map(|w| w * w).sum::<f32>() * 0.5).sqrt(),
+        );
+        if resized {
+            let plan_i = (window_size / MIN_WINDOW_SIZE).trailing_zeros() as usize;
+            (self.fft_fwd, self.fft_inv) = self.fft_plans[plan_i].clone();
+            self.reset();
         }
     }
 
+    /// Forget all the audio that's gone through, as if newly created with
+    /// the current configuration.
+    ///
+    /// Doesn't allocate, so it's safe to call from the audio thread.
+    pub fn reset(&mut self) {
+        let window_size = self.window_size;
+        self.input_buf_a.refill(window_size, 0.0);
+        self.input_buf_b.refill(window_size, 0.0);
+        self.link_buf_a.refill(window_size, 0.0);
+        self.link_buf_b.refill(window_size, 0.0);
+        self.output_buf.refill(window_size, (0.0, 0.0));
+        self.k_morph_buf.refill(window_size, 0.0);
+
+        let n_bins = self.n_bins;
+        refill(&mut self.frame_buf.0, window_size, 0.0);
+        refill(&mut self.frame_buf.1, window_size, 0.0);
+        refill(&mut self.frame_buf_low.0, window_size, 0.0);
+        refill(&mut self.frame_buf_low.1, window_size, 0.0);
+        refill(&mut self.proc_buf.0, n_bins, Complex32::default());
+        refill(&mut self.proc_buf.1, n_bins, Complex32::default());
+
+        refill(&mut self.phase_accum, n_bins, (0.0, 0.0));
+        refill(&mut self.phase_prev, n_bins, (0.0, 0.0));
+        refill(&mut self.mag_a, n_bins, 0.0);
+        refill(&mut self.mag_b, n_bins, 0.0);
+        refill(&mut self.inst_freq_b, n_bins, 0.0);
+        refill(&mut self.held_b, n_bins, (0.0, 0.0));
+        refill(&mut self.envelopes.0, n_bins, 0.0);
+        refill(&mut self.envelopes.1, n_bins, 0.0);
+        self.vocoder.clear();
+        refill(&mut self.wet.0, n_bins, 0.0);
+        refill(&mut self.wet.1, n_bins, 0.0);
+        refill(&mut self.noise_bins.0, n_bins, Complex32::default());
+        refill(&mut self.noise_bins.1, n_bins, Complex32::default());
+        self.onset_detectors.0.clear(n_bins);
+        self.onset_detectors.1.clear(n_bins);
+        refill(&mut self.link_mags, n_bins, 0.0);
+        refill(
+            &mut self.phase_resets,
+            n_bins,
+            (PhaseReset::Keep, PhaseReset::Keep),
+        );
+        self.phase_lockers.0.clear(n_bins);
+        self.phase_lockers.1.clear(n_bins);
+        refill(&mut self.committed, window_size, 0.0);
+        refill(&mut self.iter_frame, window_size, 0.0);
+        refill(&mut self.iter_buf, n_bins, Complex32::default());
+
+        self.sidechain_gate.clear();
+        refill(&mut self.fallback_mix, self.hop_length, 0.0);
+        self.noise = Noise::new();
+    }
+
+    pub fn config(&self) -> AnalysisConfig {
+        AnalysisConfig {
+            window_size: self.window_size,
+            hop_length: self.hop_length,
+            window_function: self.window_function,
+        }
+    }
     pub fn hop_length(&self) -> usize {
         self.hop_length
     }
+    /// Delay from a sample going into `morph` to it coming back out, in samples.
+    pub fn latency_samples(&self) -> usize {
+        self.config().latency_samples()
+    }
+
+    /// Whether B has been silent for long enough that the sidechain
+    /// fallback has taken over.
+    pub fn sidechain_silent(&self) -> bool {
+        self.sidechain_gate.engaged()
+    }
+
+    /// How many times a NaN or infinity has turned up and been zeroed out,
+    /// over the morpher's whole life.
+    pub fn recovered_events(&self) -> u64 {
+        self.recovered_events
+    }
 
-    fn put_inputs(&mut self, a: &[f32], b: &[f32]) {
+    fn put_inputs(
+        &mut self,
+        a: &[f32],
+        b: &[f32],
+        link: Option<(&[f32], &[f32])>,
+        k_morph: &[f32],
+        fallback: Option<SidechainFallback>,
+    ) {
         debug_assert_eq!(a.len(), self.hop_length);
         debug_assert_eq!(b.len(), self.hop_length);
+        debug_assert_eq!(k_morph.len(), self.hop_length);
 
         self.input_buf_a.push_clone_from_slice(a);
         self.input_buf_b.push_clone_from_slice(b);
+        if let Some((link_a, link_b)) = link {
+            self.link_buf_a.push_clone_from_slice(link_a);
+            self.link_buf_b.push_clone_from_slice(link_b);
+        }
+        self.k_morph_buf.push_clone_from_slice(k_morph);
+        for input_buf in [
+            &mut self.input_buf_a,
+            &mut self.input_buf_b,
+            &mut self.link_buf_a,
+            &mut self.link_buf_b,
+        ] {
+            let (lower, upper) = input_buf.slice_raw_mut(-(self.hop_length as isize), 0);
+            self.recovered_events += (sanitize(lower) + sanitize(upper)) as u64;
+        }
+
+        let hop = -(self.hop_length as isize)..0;
+        match fallback {
+            // k_morph *= 1 - fallback_mix
+            Some(SidechainFallback::PassThroughA) => {
+                for (i, mix) in hop.zip(self.fallback_mix.iter()) {
+                    self.k_morph_buf[i] *= 1.0 - mix;
+                }
+            }
+            // b = lerp<fallback_mix>(b, noise)
+            Some(SidechainFallback::Noise) => {
+                for (i, mix) in hop.zip(self.fallback_mix.iter()) {
+                    self.input_buf_b[i] = mix.lerp(self.input_buf_b[i], self.noise.next());
+                }
+            }
+            Some(SidechainFallback::HoldB) | None => {}
+        }
     }
-    fn take_outputs(&mut self) -> Vec<f32> {
-        const MIN_WINDOW_FACTOR_SUM: f32 = 0.5;
-        let mut out = vec![0.0; self.hop_length];
+    /// Take the finished hop of both branches out of `output_buf` into `out`.
+    fn take_outputs(&mut self, out: &mut [(f32, f32)]) {
+        debug_assert_eq!(out.len(), self.hop_length);
         let (lower, upper) = self.output_buf.slice_raw_mut(0, self.hop_length as isize);
-        for (i, (wave, window_factor_sum)) in lower.iter_mut().chain(upper.iter_mut()).enumerate() {
-            out[i] = *wave / window_factor_sum.max(MIN_WINDOW_FACTOR_SUM);
+        for (out, wave) in out.iter_mut().zip(lower.iter_mut().chain(upper.iter_mut())) {
+            *out = *wave;
             // reset for next time.
-            *wave = 0.0;
-            *window_factor_sum = 0.0;
+            *wave = (0.0, 0.0);
+        }
+        for out in out.iter_mut() {
+            if !(out.0.is_finite() && out.1.is_finite()) {
+                *out = (0.0, 0.0);
+                self.recovered_events += 1;
+            }
         }
         self.output_buf.shift(self.hop_length as isize);
-        out
+    }
+
+    /// Push the next frame into the onset detectors: A and B's, or when
+    /// `linked`, the link signals'.
+    fn detect_onsets(&mut self, linked: bool) {
+        if !linked {
+            self.onset_detectors.0.push(&self.mag_a);
+            self.onset_detectors.1.push(&self.mag_b);
+            return;
+        }
+        for (link_buf, detector) in [
+            (&self.link_buf_a, &mut self.onset_detectors.0),
+            (&self.link_buf_b, &mut self.onset_detectors.1),
+        ] {
+            Self::take_windowed_input(&self.analysis_window, link_buf, &mut self.iter_frame);
+            Self::fft_forward(
+                &self.fft_fwd,
+                &mut self.iter_frame,
+                &mut self.iter_buf,
+                &mut self.fft_scratch,
+            );
+            for (mag, bin) in self.link_mags.iter_mut().zip(self.iter_buf.iter()) {
+                *mag = if bin.is_finite() { bin.norm() } else { 0.0 };
+            }
+            detector.push(&self.link_mags);
+        }
+    }
+
+    /// Decide what happens to each bin's phase in both branches this hop.
+    ///
+    /// In a branch, each input has a share of the mix: `1 - k` for the one
+    /// it's morphing from (A in A -> B) and `k` for the one it's morphing
+    /// into. An onset in an input, with its level scaled by its share, resets
+    /// the phase to that input's. If both have one, the input with the larger
+    /// share wins, the one being morphed from on a tie.
+    ///
+    /// So the branches mirror each other: swapping A and B swaps them.
+    fn find_phase_resets(&mut self, k_morph: f32, sensitivity: f32, scope: ResetScope) {
+        let Some(threshold) = onset_threshold(sensitivity) else {
+            self.phase_resets.fill((PhaseReset::Keep, PhaseReset::Keep));
+            return;
+        };
+        // reset = from if (onset(from) && (!onset(into) || k <= 0.5)),
+        //         into if (onset(into) && (!onset(from) || k > 0.5))
+        let pick = |from: (bool, PhaseReset), into: (bool, PhaseReset)| match (from.0, into.0) {
+            (true, true) if k_morph > 0.5 => into.1,
+            (true, _) => from.1,
+            (false, true) => into.1,
+            (false, false) => PhaseReset::Keep,
+        };
+        let (detector_a, detector_b) = &self.onset_detectors;
+        for (i, resets) in self.phase_resets.iter_mut().enumerate() {
+            let onset = |detector: &OnsetDetector, weight: f32| {
+                detector.is_onset(i, weight, threshold, scope)
+            };
+            //// for A -> B morph
+            resets.0 = pick(
+                (onset(detector_a, 1.0 - k_morph), PhaseReset::ToA),
+                (onset(detector_b, k_morph), PhaseReset::ToB),
+            );
+            //// for B -> A morph
+            resets.1 = pick(
+                (onset(detector_b, 1.0 - k_morph), PhaseReset::ToB),
+                (onset(detector_a, k_morph), PhaseReset::ToA),
+            );
+        }
+    }
+
+    /// Morphed magnitudes of bin `i` in the (A -> B, B -> A) branches.
+    fn morph_magnitudes(
+        &self,
+        combine: CombineMode,
+        law: MagnitudeLaw,
+        k_morph: f32,
+        i: usize,
+    ) -> (f32, f32) {
+        let mag = (self.mag_a[i], self.mag_b[i]);
+        match combine {
+            CombineMode::Blend => (
+                law.morph(k_morph, mag.0, mag.1), // A -> B
+                law.morph(k_morph, mag.1, mag.0), // B -> A
+            ),
+            CombineMode::CrossSynthesis {
+                timbre, excitation, ..
+            } => {
+                // mag_morphed = morph<k * timbre>(envelope) * morph<k * excitation>(mag / envelope)
+                let envelope = (self.envelopes.0[i], self.envelopes.1[i]);
+                let fine = (mag.0 / envelope.0, mag.1 / envelope.1);
+                let (k_envelope, k_fine) = (k_morph * timbre, k_morph * excitation);
+                (
+                    law.morph(k_envelope, envelope.0, envelope.1)
+                        * law.morph(k_fine, fine.0, fine.1), // A -> B
+                    law.morph(k_envelope, envelope.1, envelope.0)
+                        * law.morph(k_fine, fine.1, fine.0), // B -> A
+                )
+            }
+            CombineMode::Vocoder(_) | CombineMode::Convolution { .. } => (
+                law.morph(k_morph, mag.0, self.wet.0[i]), // A -> B
+                law.morph(k_morph, mag.1, self.wet.1[i]), // B -> A
+            ),
+        }
+    }
+
+    /// Add `k_morph` of the vocoder's noise to both branches' spectra in
+    /// `proc_buf`.
+    fn add_noise(&mut self, k_morph: f32) {
+        for (bin, noise) in self.proc_buf.0.iter_mut().zip(self.noise_bins.0.iter()) {
+            *bin += noise * k_morph;
+        }
+        for (bin, noise) in self.proc_buf.1.iter_mut().zip(self.noise_bins.1.iter()) {
+            *bin += noise * k_morph;
+        }
     }
 
     fn take_windowed_input(
-        window_func: &Vec<f32>,
+        window_func: &[f32],
         input_buf: &RingBuffer<f32>,
-        windowed_inputs: &mut [Complex32],
+        windowed_inputs: &mut [f32],
     ) {
         let window_size = window_func.len();
         debug_assert_eq!(input_buf.len(), window_size);
@@ -105,105 +667,1357 @@ impl Morpher {
         let (lower, upper) = input_buf.slice_raw(0, window_size as isize);
 
         for (i, value) in lower.iter().chain(upper.iter()).enumerate() {
-            windowed_inputs[i] = (window_func[i] * value).into();
+            windowed_inputs[i] = window_func[i] * value;
+        }
+    }
+
+    /// One iteration of real-time iterative spectrogram inversion (RTISI, a
+    /// streaming take on Griffin-Lim).
+    ///
+    /// Resynthesizes `spectrum`, adds it to the `committed` overlap of the
+    /// frames before it, then re-analyses that estimate and keeps its phase,
+    /// with `spectrum`'s magnitudes. Each pass brings the phase closer to one
+    /// that's consistent with the overlapping history.
+    ///
+    /// Later frames haven't been added in yet, so the estimate is scaled up
+    /// by `partial_window_sum` to make up for them.
+    fn refine_phase(
+        &self,
+        spectrum: &mut [Complex32],
+        committed: &[f32],
+        frame: &mut [f32],
+        scratch: &mut [Complex32],
+        fft_scratch: &mut [Complex32],
+    ) {
+        scratch.copy_from_slice(spectrum);
+        Self::fft_inverse(&self.fft_inv, scratch, frame, fft_scratch);
+        for (i, value) in frame.iter_mut().enumerate() {
+            let estimate = (committed[i]
+                + *value * self.synthesis_window[i] / self.window_size as f32)
+                / self.partial_window_sum[i].max(f32::EPSILON);
+            *value = estimate * self.analysis_window[i];
+        }
+        Self::fft_forward(&self.fft_fwd, frame, scratch, fft_scratch);
+        for (bin, estimate) in spectrum.iter_mut().zip(scratch.iter()) {
+            *bin = Complex32::from_polar(bin.norm(), estimate.arg());
+        }
+    }
+
+    /// Transform a real `frame` into the `n_bins` of `spectrum`. `frame` is
+    /// used as scratch.
+    fn fft_forward(
+        plan: &FftPlanFwd,
+        frame: &mut [f32],
+        spectrum: &mut [Complex32],
+        scratch: &mut [Complex32],
+    ) {
+        let result = plan.process_with_scratch(frame, spectrum, scratch);
+        debug_assert!(result.is_ok(), "Morpher: {result:?}");
+    }
+    /// Transform the `n_bins` of `spectrum` back into a real `frame`.
+    /// `spectrum` is used as scratch.
+    ///
+    /// A real signal has no imaginary part at DC or Nyquist, so whatever's
+    /// there is dropped first.
+    fn fft_inverse(
+        plan: &FftPlanInv,
+        spectrum: &mut [Complex32],
+        frame: &mut [f32],
+        scratch: &mut [Complex32],
+    ) {
+        let nyquist = spectrum.len() - 1;
+        spectrum[0].im = 0.0;
+        spectrum[nyquist].im = 0.0;
+        let result = plan.process_with_scratch(spectrum, frame, scratch);
+        debug_assert!(result.is_ok(), "Morpher: {result:?}");
+    }
+
+    /// Zero out the non-finite bins of `spectrum`, returning how many there were.
+    fn sanitize_spectrum(spectrum: &mut [Complex32]) -> usize {
+        let mut count = 0;
+        for bin in spectrum.iter_mut().filter(|bin| !bin.is_finite()) {
+            *bin = Complex32::default();
+            count += 1;
+        }
+        count
+    }
+
+    /// Lock `locker`'s phases and rebuild the branch's `spectrum` from them.
+    fn apply_phase_lock(mode: PhaseLocking, locker: &mut PhaseLocker, spectrum: &mut [Complex32]) {
+        locker.lock(mode);
+        for (bin, (&mag, &phase)) in spectrum
+            .iter_mut()
+            .zip(locker.mags.iter().zip(locker.phase.iter()))
+        {
+            *bin = Complex32::from_polar(mag, phase);
+        }
+    }
+
+    /// Resynthesize both branches from `phase_accum`, with their magnitudes
+    /// morphed at `k_morph`, into `frame_buf_low` if `low`, or `frame_buf`.
+    fn resynthesize(&mut self, combine: CombineMode, law: MagnitudeLaw, k_morph: f32, low: bool) {
+        for i in 0..self.n_bins {
+            let mag = self.morph_magnitudes(combine, law, k_morph, i);
+            self.proc_buf.0[i] = Complex32::from_polar(mag.0, self.phase_accum[i].0);
+            self.proc_buf.1[i] = Complex32::from_polar(mag.1, self.phase_accum[i].1);
+        }
+        if let CombineMode::Vocoder(_) = combine {
+            self.add_noise(k_morph);
         }
+        let frame_buf = if low {
+            &mut self.frame_buf_low
+        } else {
+            &mut self.frame_buf
+        };
+        Self::fft_inverse(
+            &self.fft_inv,
+            &mut self.proc_buf.0,
+            &mut frame_buf.0,
+            &mut self.fft_scratch,
+        );
+        Self::fft_inverse(
+            &self.fft_inv,
+            &mut self.proc_buf.1,
+            &mut frame_buf.1,
+            &mut self.fft_scratch,
+        );
     }
 
-    /// Morph one `hop_length` of samples.
+    /// Morph one `hop_length` of samples, writing the hop of both branches
+    /// that's finished into `out`. `k_morph` goes with each sample of `a`
+    /// and `b`.
     pub fn morph(
         &mut self,
         a: &[f32],
         b: &[f32],
-        k_morph: f32,
-        k_fade: f32,
-        _aux_spectral_spread: f32,
-        _iter_count: i32,
-    ) -> Vec<f32> {
-        self.put_inputs(a, b);
+        k_morph: &[f32],
+        settings: &MorphSettings,
+        out: &mut [(f32, f32)],
+    ) {
+        self.morph_linked(a, b, None, k_morph, settings, out);
+    }
+
+    /// Like `morph`, but with onsets detected in the `link` signals standing
+    /// in for A and B, if given. Morphers linked to the same signals reset
+    /// their phases together.
+    pub fn morph_linked(
+        &mut self,
+        a: &[f32],
+        b: &[f32],
+        link: Option<(&[f32], &[f32])>,
+        k_morph: &[f32],
+        settings: &MorphSettings,
+        out: &mut [(f32, f32)],
+    ) {
+        let MorphSettings {
+            aux_spectral_spread,
+            iter_count,
+            combine,
+            magnitude_law,
+            phase_locking,
+            onset_sensitivity,
+            reset_scope,
+            sidechain_fallback,
+            sidechain_hold,
+        } = *settings;
+
+        // fallback_mix = fade(silent(b) for sidechain_hold)
+        //// B has to stay silent for a while for the fallback to take over, and come back
+        //// properly to let it go, and either way it fades over a window rather than
+        //// switching from one frame to the next.
+        self.sidechain_gate
+            .process(b, sidechain_hold, self.window_size, &mut self.fallback_mix);
+        let fallback_k = self.fallback_mix.iter().sum::<f32>() / self.hop_length as f32;
+        //// when B only shapes A, B going silent is its levels falling to nothing, and passing
+        //// A through would turn the output up instead.
+        let fallback = (fallback_k > 0.0)
+            .then_some(sidechain_fallback)
+            .filter(|&fallback| {
+                !(fallback == SidechainFallback::PassThroughA && combine.b_shapes_a())
+            });
+        self.put_inputs(a, b, link, k_morph, fallback);
+
+        // the frame's magnitudes follow k_morph between the lowest and highest it gets to
+        // over the frame, phases advance by k_morph averaged over the new hop.
+        //// passing A through is morphing A with itself, from A's side, in A's phase.
+        let passing_through =
+            fallback == Some(SidechainFallback::PassThroughA) && fallback_k == 1.0;
+        let (k_morph_range, k_morph_end, k_morph) = if passing_through {
+            ((0.0, 0.0), 0.0, 0.0)
+        } else {
+            let (lower, upper) = self.k_morph_buf.slice_raw(0, self.window_size as isize);
+            let k_morph_range = lower
+                .iter()
+                .chain(upper.iter())
+                .fold((f32::MAX, f32::MIN), |(low, high), &k| {
+                    (low.min(k), high.max(k))
+                });
+            let (lower, upper) = self.k_morph_buf.slice_raw(-(self.hop_length as isize), 0);
+            let k_morph = lower.iter().chain(upper.iter()).sum::<f32>() / self.hop_length as f32;
+            (k_morph_range, self.k_morph_buf[-1], k_morph)
+        };
 
         // <load> input
         // input *= window_fn
         Self::take_windowed_input(
-            &self.window_func,
-            &mut self.input_buf_a,
-            &mut self.proc_buf.0,
+            &self.analysis_window,
+            &self.input_buf_a,
+            &mut self.frame_buf.0,
         );
         Self::take_windowed_input(
-            &self.window_func,
-            &mut self.input_buf_b,
+            &self.analysis_window,
+            &self.input_buf_b,
+            &mut self.frame_buf.1,
+        );
+
+        // (mag, phase) = rfft(input)   [unnormalized]
+        //// only bins 0..=nyquist, the rest of a real signal's spectrum mirrors them
+        Self::fft_forward(
+            &self.fft_fwd,
+            &mut self.frame_buf.0,
+            &mut self.proc_buf.0,
+            &mut self.fft_scratch,
+        );
+        Self::fft_forward(
+            &self.fft_fwd,
+            &mut self.frame_buf.1,
             &mut self.proc_buf.1,
+            &mut self.fft_scratch,
         );
 
-        // (mag, phase) = fft(input)   [unnormalized]
-        self.fft_fwd.process(&mut self.proc_buf.0);
-        self.fft_fwd.process(&mut self.proc_buf.1);
+        // if (!finite(bin)) bin = 0
+        //// huge but finite input can still overflow the transform
+        for spectrum in [&mut self.proc_buf.0, &mut self.proc_buf.1] {
+            self.recovered_events += Self::sanitize_spectrum(spectrum) as u64;
+        }
+
+        // mag_a = abs(fft(a)), mag_b = spread(abs(fft(b)))
+        for i in 0..self.n_bins {
+            self.mag_a[i] = self.proc_buf.0[i].norm();
+            self.mag_b[i] = self.proc_buf.1[i].norm();
+        }
+        self.spread.apply(
+            &mut self.mag_b,
+            aux_spectral_spread * MAX_SPECTRAL_SPREAD_OCTAVES,
+        );
+
+        // held_b.phase += inst_freq_b   [unless B is sounding]
+        //// B's partials keep turning at the frequencies they had, ready to be held
+        let tracking_b = self.sidechain_gate.open();
+        if !tracking_b {
+            for (held, inst_freq) in self.held_b.iter_mut().zip(self.inst_freq_b.iter()) {
+                held.1 = wrap_phase(held.1 + inst_freq);
+            }
+        }
+        // (bin, mag)_b = lerp<fallback_k>((bin, mag)_b, fallback's)
+        match fallback {
+            Some(SidechainFallback::PassThroughA) => {
+                for i in 0..self.n_bins {
+                    self.proc_buf.1[i] = fallback_k.lerp(self.proc_buf.1[i], self.proc_buf.0[i]);
+                    self.mag_b[i] = fallback_k.lerp(self.mag_b[i], self.mag_a[i]);
+                }
+            }
+            Some(SidechainFallback::HoldB) => {
+                for i in 0..self.n_bins {
+                    let (mag, phase) = self.held_b[i];
+                    let held = Complex32::from_polar(mag, phase);
+                    self.proc_buf.1[i] = fallback_k.lerp(self.proc_buf.1[i], held);
+                    self.mag_b[i] = fallback_k.lerp(self.mag_b[i], mag);
+                }
+            }
+            //// already mixed into B
+            Some(SidechainFallback::Noise) | None => {}
+        }
+
+        match combine {
+            CombineMode::Blend => {}
+            // envelope = exp(lifter(cepstrum(mag)))
+            CombineMode::CrossSynthesis { lifter_order, .. } => {
+                let plans = (&self.fft_fwd, &self.fft_inv);
+                for (mags, envelope) in [
+                    (&self.mag_a, &mut self.envelopes.0),
+                    (&self.mag_b, &mut self.envelopes.1),
+                ] {
+                    self.envelope.extract(
+                        plans,
+                        &mut self.fft_scratch,
+                        mags,
+                        lifter_order,
+                        envelope,
+                    );
+                }
+            }
+            // wet = carrier * follow(rms(modulator band)) / rms(carrier)
+            CombineMode::Vocoder(vocoder) => self.vocoder.process(
+                &vocoder,
+                self.hop_length,
+                (&self.mag_a, &self.mag_b),
+                (&mut self.wet.0, &mut self.wet.1),
+                (&mut self.noise_bins.0, &mut self.noise_bins.1),
+                &mut self.noise,
+            ),
+            // wet = mag * mag_other / envelope(mag_other)^whitening
+            CombineMode::Convolution {
+                whitening,
+                normalize,
+            } => {
+                let plans = (&self.fft_fwd, &self.fft_inv);
+                for (mags, envelope) in [
+                    (&self.mag_a, &mut self.envelopes.0),
+                    (&self.mag_b, &mut self.envelopes.1),
+                ] {
+                    self.envelope.extract(
+                        plans,
+                        &mut self.fft_scratch,
+                        mags,
+                        WHITENING_LIFTER_ORDER,
+                        envelope,
+                    );
+                }
+                convolve(
+                    (&self.mag_a, &self.mag_b),
+                    (&self.envelopes.0, &self.envelopes.1),
+                    whitening,
+                    normalize,
+                    //// what a full-scale B filters with, once whitened
+                    self.full_scale.0 / self.full_scale.1.powf(whitening),
+                    (&mut self.wet.0, &mut self.wet.1),
+                );
+            }
+        }
+        //// cross-synthesis takes its phases from the excitation
+        let k_phase = combine.phase_k(k_morph);
+
+        // onsets = flux(mag, mag_prev) > threshold(sensitivity)
+        //// linked channels all look for onsets in the same signals, so they reset together
+        self.detect_onsets(link.is_some());
+        self.find_phase_resets(k_phase, onset_sensitivity, reset_scope);
 
         // # morphing interpolation
-        for i in 0..self.window_size {
+        let bin_advance = std::f32::consts::TAU * self.hop_length as f32 / self.window_size as f32;
+        for i in 0..self.n_bins {
             // const BIN_MAGNITUDE_FADE_COEFFICIENTS: (f32, f32) = (0.0, 0.6);
 
-            // (mag, phase)
+            // phase
             let phase = (self.proc_buf.0[i].arg(), self.proc_buf.1[i].arg());
-            let mag = (self.proc_buf.0[i].abs(), self.proc_buf.1[i].abs());
+            let bin = (self.proc_buf.0[i], self.proc_buf.1[i]);
 
-            // phase_delta = phase - phase_prev
-            let phase_delta = (
-                phase.0 - self.phase_prev[i].0,
-                phase.1 - self.phase_prev[i].1,
+            // inst_freq = expected + wrap(phase - phase_prev - expected)   [radians per hop]
+            //// a sinusoid centered on bin i advances by `i * bin_advance` every hop,
+            //// whatever's left over is how far off-center its true frequency is.
+            let expected_advance = wrap_phase(i as f32 * bin_advance);
+            let inst_freq = (
+                expected_advance + wrap_phase(phase.0 - self.phase_prev[i].0 - expected_advance),
+                expected_advance + wrap_phase(phase.1 - self.phase_prev[i].1 - expected_advance),
             );
+            if tracking_b {
+                self.inst_freq_b[i] = inst_freq.1;
+                self.held_b[i] = (self.mag_b[i], phase.1);
+            }
             // self.mag_faded[i] = BIN_MAGNITUDE_FADE_COEFFICIENTS.lerp(mag, self.mag_faded[i]);
 
-            // phase_accum += lerp<k>(phase_delta[..])
-            self.phase_accum[i].0 += k_morph.lerp(phase_delta.0, phase_delta.1); // A -> B
-            self.phase_accum[i].1 += k_morph.lerp(phase_delta.1, phase_delta.0); // B -> A
+            // (bin, phase, inst_freq)_wet = the other input's, or (a * b)'s when convolving
+            //// each branch morphs from its own input to the wet side. Multiplying spectra
+            //// adds their phases, but what comes through is in both at once, at about the
+            //// same frequency, so the convolved side turns at their mean.
+            let (bin_wet, phase_wet, inst_freq_wet) = match combine {
+                CombineMode::Convolution { .. } => {
+                    let phase = phase.0 + phase.1;
+                    let inst_freq = (inst_freq.0 + inst_freq.1) * 0.5;
+                    (
+                        (
+                            Complex32::from_polar(self.wet.0[i], phase),
+                            Complex32::from_polar(self.wet.1[i], phase),
+                        ),
+                        (phase, phase),
+                        (inst_freq, inst_freq),
+                    )
+                }
+                _ => (
+                    (bin.1, bin.0),
+                    (phase.1, phase.0),
+                    (inst_freq.1, inst_freq.0),
+                ),
+            };
+
+            //// phase locking works relative to the phase of the complex mix of both sides
+            self.phase_lockers.0.reference[i] = k_phase.lerp(bin.0, bin_wet.0).arg();
+            self.phase_lockers.1.reference[i] = k_phase.lerp(bin.1, bin_wet.1).arg();
+            self.phase_lockers.0.phase_prev[i] = self.phase_accum[i].0;
+            self.phase_lockers.1.phase_prev[i] = self.phase_accum[i].1;
+
+            // phase_accum = wrap(phase_accum + lerp<k>(inst_freq[..]))
+            let advance = (
+                k_phase.lerp(inst_freq.0, inst_freq_wet.0), // A -> B
+                k_phase.lerp(inst_freq.1, inst_freq_wet.1), // B -> A
+            );
+            self.phase_accum[i].0 = wrap_phase(self.phase_accum[i].0 + advance.0);
+            self.phase_accum[i].1 = wrap_phase(self.phase_accum[i].1 + advance.1);
+
+            // if (onset) phase_accum = phase
+            if passing_through {
+                self.phase_accum[i] = phase;
+            } else {
+                //// B's side of A -> B and A's side of B -> A are the wet side
+                let reset = |reset: PhaseReset, phase_accum: &mut f32, (to_a, to_b): (f32, f32)| {
+                    match reset {
+                        PhaseReset::Keep => {}
+                        PhaseReset::ToA => *phase_accum = to_a,
+                        PhaseReset::ToB => *phase_accum = to_b,
+                    }
+                };
+                reset(
+                    self.phase_resets[i].0,
+                    &mut self.phase_accum[i].0,
+                    (phase.0, phase_wet.0),
+                );
+                reset(
+                    self.phase_resets[i].1,
+                    &mut self.phase_accum[i].1,
+                    (phase_wet.1, phase.1),
+                );
+            }
+
+            // ...prev = ...current
+            self.phase_prev[i] = phase;
+
+            let mag_morphed = self.morph_magnitudes(combine, magnitude_law, k_morph_end, i);
+            self.phase_lockers.0.mags[i] = mag_morphed.0;
+            self.phase_lockers.1.mags[i] = mag_morphed.1;
+            self.phase_lockers.0.advance[i] = advance.0;
+            self.phase_lockers.1.advance[i] = advance.1;
+            self.phase_lockers.0.phase[i] = self.phase_accum[i].0;
+            self.phase_lockers.1.phase[i] = self.phase_accum[i].1;
+
+            // reconstructed = complex(r= mag_morphed, theta= phase_accum)
+            self.proc_buf.0[i] = Complex32::from_polar(mag_morphed.0, self.phase_accum[i].0);
+            self.proc_buf.1[i] = Complex32::from_polar(mag_morphed.1, self.phase_accum[i].1);
+        }
+
+        // # phase locking
+        // phase_accum = lock(phase_accum) around peaks of mag_morphed
+        if phase_locking != PhaseLocking::Off {
+            Self::apply_phase_lock(
+                phase_locking,
+                &mut self.phase_lockers.0,
+                &mut self.proc_buf.0,
+            );
+            Self::apply_phase_lock(
+                phase_locking,
+                &mut self.phase_lockers.1,
+                &mut self.proc_buf.1,
+            );
+            for (i, phase_accum) in self.phase_accum.iter_mut().enumerate() {
+                *phase_accum = (self.phase_lockers.0.phase[i], self.phase_lockers.1.phase[i]);
+            }
+        }
+
+        // # iterative phase reconstruction
+        // phase_accum = arg(refine^iter_count(reconstructed))
+        if iter_count > 0 {
+            let mut committed = std::mem::take(&mut self.committed);
+            let mut iter_frame = std::mem::take(&mut self.iter_frame);
+            let mut iter_buf = std::mem::take(&mut self.iter_buf);
+            let mut fft_scratch = std::mem::take(&mut self.fft_scratch);
+            let mut proc_buf = std::mem::take(&mut self.proc_buf);
 
-            // if (mag/mag_prev > 10) phase_accum = phase
             //// for A -> B morph
-            if mag.0 / self.mag_prev[i].0 * (1.0 - k_morph) > 10.0 {
-                self.phase_accum[i].0 = phase.0
+            for (i, value) in committed.iter_mut().enumerate() {
+                *value = self.output_buf[i as isize].0;
             }
-            if mag.1 / self.mag_prev[i].1 * (k_morph) > 10.0 {
-                self.phase_accum[i].0 = phase.1
+            for _ in 0..iter_count {
+                self.refine_phase(
+                    &mut proc_buf.0,
+                    &committed,
+                    &mut iter_frame,
+                    &mut iter_buf,
+                    &mut fft_scratch,
+                );
             }
             //// for B -> A morph
-            if mag.1 / self.mag_prev[i].1 * (1.0 - k_morph) > 10.0 {
-                self.phase_accum[i].1 = phase.1
+            for (i, value) in committed.iter_mut().enumerate() {
+                *value = self.output_buf[i as isize].1;
             }
-            if mag.0 / self.mag_prev[i].0 * (k_morph) > 10.0 {
-                self.phase_accum[i].1 = phase.0
+            for _ in 0..iter_count {
+                self.refine_phase(
+                    &mut proc_buf.1,
+                    &committed,
+                    &mut iter_frame,
+                    &mut iter_buf,
+                    &mut fft_scratch,
+                );
             }
 
-            // ...prev = ...current
-            self.phase_prev[i] = phase;
-            self.mag_prev[i] = mag;
+            for (i, phase_accum) in self.phase_accum.iter_mut().enumerate() {
+                *phase_accum = (proc_buf.0[i].arg(), proc_buf.1[i].arg());
+            }
 
-            // reconstructed = complex(r= mag_fade, theta= phase_accum)
-            //// for A -> B morph
-            self.proc_buf.0[i] = Complex32::from_polar(
-                // k.lerp(self.mag_faded[i].0, self.mag_faded[i].1), !!!!!!!!!!!!!!!!!!!!!!!
-                mag.0.powf((1.0 - k_morph).sqrt()) * mag.1.powf(k_morph.sqrt()),
-                self.phase_accum[i].0,
+            self.committed = committed;
+            self.iter_frame = iter_frame;
+            self.iter_buf = iter_buf;
+            self.fft_scratch = fft_scratch;
+            self.proc_buf = proc_buf;
+        }
+
+        // reconstructed += k_morph * noise   [vocoder]
+        //// added after phase reconstruction, so its random phases stay out of phase_accum
+        if let CombineMode::Vocoder(_) = combine {
+            self.add_noise(k_morph_end);
+        }
+
+        // if (!finite(reconstructed)) reconstructed = 0, phase_accum = phase
+        //// a bin that blew up starts over from the analysis phase, rather than
+        //// carrying NaN in its phase forever
+        for i in 0..self.n_bins {
+            let finite = |bin: Complex32, phase: f32| bin.is_finite() && phase.is_finite();
+            if !finite(self.proc_buf.0[i], self.phase_accum[i].0) {
+                self.proc_buf.0[i] = Complex32::default();
+                self.phase_accum[i].0 = self.phase_prev[i].0;
+                self.recovered_events += 1;
+            }
+            if !finite(self.proc_buf.1[i], self.phase_accum[i].1) {
+                self.proc_buf.1[i] = Complex32::default();
+                self.phase_accum[i].1 = self.phase_prev[i].1;
+                self.recovered_events += 1;
+            }
+        }
+
+        // output_windowed = irfft(reconstructed) * synthesis_window / window_size
+        Self::fft_inverse(
+            &self.fft_inv,
+            &mut self.proc_buf.0,
+            &mut self.frame_buf.0,
+            &mut self.fft_scratch,
+        );
+        Self::fft_inverse(
+            &self.fft_inv,
+            &mut self.proc_buf.1,
+            &mut self.frame_buf.1,
+            &mut self.fft_scratch,
+        );
+
+        // output_windowed = lerp<t>(output_windowed<k_low>, output_windowed<k_high>)
+        //// if k_morph moved at all over the frame, even if it came back to where it started,
+        //// resynthesize at the lowest and highest it got to and crossfade between the two as
+        //// k_morph goes, so the morph follows it sample by sample.
+        let (k_morph_low, k_morph_high) = k_morph_range;
+        let k_morph_moved = k_morph_low != k_morph_high;
+        if k_morph_moved {
+            //// frame_buf has k_morph_end's, which may already be one of them
+            if k_morph_end == k_morph_low {
+                std::mem::swap(&mut self.frame_buf, &mut self.frame_buf_low);
+            } else {
+                self.resynthesize(combine, magnitude_law, k_morph_low, true);
+            }
+            if k_morph_end != k_morph_high {
+                self.resynthesize(combine, magnitude_law, k_morph_high, false);
+            }
+        }
+        //// a grain that still overflowed is dropped, so it can't poison output_buf
+        let frames = [
+            &mut self.frame_buf.0,
+            &mut self.frame_buf.1,
+            &mut self.frame_buf_low.0,
+            &mut self.frame_buf_low.1,
+        ];
+        let n_frames = if k_morph_moved { 4 } else { 2 };
+        for frame in frames.into_iter().take(n_frames) {
+            if frame.iter().any(|value| !value.is_finite()) {
+                frame.fill(0.0);
+                self.recovered_events += 1;
+            }
+        }
+        for i in 0..self.window_size {
+            let window_factor = self.synthesis_window[i] / self.window_size as f32;
+            let mut grain = (self.frame_buf.0[i], self.frame_buf.1[i]);
+            if k_morph_moved {
+                let t = self.k_morph_buf[i as isize]
+                    .invlerp(k_morph_low, k_morph_high)
+                    .clamp(0.0, 1.0);
+                grain = (
+                    t.lerp(self.frame_buf_low.0[i], grain.0),
+                    t.lerp(self.frame_buf_low.1[i], grain.1),
+                );
+            }
+            let wave = &mut self.output_buf[i as isize];
+            wave.0 += grain.0 * window_factor;
+            wave.1 += grain.1 * window_factor;
+        }
+
+        self.take_outputs(out);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        rms, window_size_for_ms, AnalysisConfig, CombineMode, MagnitudeLaw, MorphSettings, Morpher,
+        PhaseLocking, PhaseReset, ResetScope, SidechainFallback, VocoderSettings, MAX_WINDOW_SIZE,
+        MIN_WINDOW_SIZE,
+    };
+    use crate::util::lerpable::Lerpable;
+
+    /// Run `a` and `b` through a fresh `Morpher` hop by hop.
+    fn render(a: &[f32], b: &[f32], k_morph: f32, settings: &MorphSettings) -> (Vec<f32>, usize) {
+        render_with(a, b, &vec![k_morph; a.len()], settings)
+    }
+    /// Like `render`, with `k_morph` changing sample by sample.
+    fn render_with(
+        a: &[f32],
+        b: &[f32],
+        k_morph: &[f32],
+        settings: &MorphSettings,
+    ) -> (Vec<f32>, usize) {
+        let (out, latency) = render_branches(a, b, k_morph, settings);
+        let out = out.into_iter().map(|(a_to_b, _)| a_to_b).collect();
+        (out, latency)
+    }
+    /// Like `render_with`, returning both the (A -> B, B -> A) branches.
+    fn render_branches(
+        a: &[f32],
+        b: &[f32],
+        k_morph: &[f32],
+        settings: &MorphSettings,
+    ) -> (Vec<(f32, f32)>, usize) {
+        let mut morpher = Morpher::new();
+        let hop_length = morpher.hop_length();
+        let mut out = vec![(0.0, 0.0); a.len()];
+        for (((a, b), k_morph), out) in a
+            .chunks_exact(hop_length)
+            .zip(b.chunks_exact(hop_length))
+            .zip(k_morph.chunks_exact(hop_length))
+            .zip(out.chunks_exact_mut(hop_length))
+        {
+            morpher.morph(a, b, k_morph, settings, out);
+        }
+        (out, morpher.latency_samples())
+    }
+
+    /// A vocoder with bands about half an octave apart, and no formant
+    /// shift or noise.
+    const VOCODER: VocoderSettings = VocoderSettings {
+        bands: 16,
+        freq_range: (0.002, 0.4),
+        attack: 64.0,
+        release: 512.0,
+        formant_shift: 0.0,
+        noise: 0.0,
+    };
+
+    fn sines(len: usize, freqs: &[f32]) -> Vec<f32> {
+        (0..len)
+            .map(|i| freqs.iter().map(|f| (i as f32 * f).sin()).sum())
+            .collect()
+    }
+
+    /// Harmonics of `f0` up to about 0.4 of the sample rate, each at
+    /// `amp(frequency)`.
+    fn harmonics(len: usize, f0: f32, amp: fn(f32) -> f32) -> Vec<f32> {
+        (0..len)
+            .map(|i| {
+                (1..)
+                    .map(|h| h as f32 * f0)
+                    .take_while(|&f| f < 2.5)
+                    .map(|f| amp(f) * (i as f32 * f).sin())
+                    .sum()
+            })
+            .collect()
+    }
+
+    /// Amplitude of the sinusoid at `freq` in `samples`.
+    fn amplitude(samples: &[f32], freq: f32) -> f32 {
+        let (re, im) = samples
+            .iter()
+            .enumerate()
+            .map(|(i, x)| (x * (i as f32 * freq).cos(), x * (i as f32 * freq).sin()))
+            .fold((0.0, 0.0), |sum, x| (sum.0 + x.0, sum.1 + x.1));
+        (re * re + im * im).sqrt() * 2.0 / samples.len() as f32
+    }
+
+    #[test]
+    fn morpher_iterations_and_locking_keep_identity() {
+        let a = sines(16384, &[0.031, 0.0071]);
+        let b = sines(16384, &[0.05]);
+        for iter_count in [0, 1, 5] {
+            for phase_locking in [
+                PhaseLocking::Off,
+                PhaseLocking::Identity,
+                PhaseLocking::Scaled,
+            ] {
+                let settings = MorphSettings {
+                    iter_count,
+                    phase_locking,
+                    ..Default::default()
+                };
+                let (out, latency) = render(&a, &b, 0.0, &settings);
+                for i in 4096..out.len() {
+                    assert!(
+                        (out[i] - a[i - latency]).abs() < 1e-3,
+                        "{settings:?}, sample {i}"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn morpher_identity_at_both_ends() {
+        // fully on one side, each branch is one input, whatever the law
+        let a = sines(16384, &[0.031, 0.0071]);
+        let b = sines(16384, &[0.05, 0.11]);
+        let laws = [
+            MagnitudeLaw::LinearAmplitude,
+            MagnitudeLaw::LinearPower,
+            MagnitudeLaw::Geometric,
+            MagnitudeLaw::Decibel,
+            MagnitudeLaw::Curve { shape: 0.5 },
+        ];
+        let cross_synthesis = CombineMode::CrossSynthesis {
+            timbre: 1.0,
+            excitation: 1.0,
+            lifter_order: 30,
+        };
+        let combines = [CombineMode::Blend].into_iter().cycle().zip(laws);
+        for (combine, magnitude_law) in combines.chain([(cross_synthesis, MagnitudeLaw::Geometric)])
+        {
+            let settings = MorphSettings {
+                combine,
+                magnitude_law,
+                ..Default::default()
+            };
+            for (k_morph, expected) in [(0.0, (&a, &b)), (1.0, (&b, &a))] {
+                let (out, latency) = render_branches(&a, &b, &vec![k_morph; a.len()], &settings);
+                for (i, out) in out.iter().enumerate().skip(4096) {
+                    let expected = (expected.0[i - latency], expected.1[i - latency]);
+                    assert!(
+                        (out.0 - expected.0).abs() < 1e-3 && (out.1 - expected.1).abs() < 1e-3,
+                        "{combine:?}, {magnitude_law:?}, k = {k_morph}, sample {i}"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn morpher_cross_synthesis_splits_timbre_from_pitch() {
+        // A has flat harmonics, B a lower pitch with its harmonics rolling off
+        const F0_A: f32 = 0.0731;
+        const F0_B: f32 = 0.0257;
+        let a = harmonics(16384, F0_A, |_| 0.1);
+        let b = harmonics(16384, F0_B, |f| 0.1 * (-f / 0.1).exp());
+        let render_cross = |timbre: f32, excitation: f32| {
+            let settings = MorphSettings {
+                combine: CombineMode::CrossSynthesis {
+                    timbre,
+                    excitation,
+                    lifter_order: 30,
+                },
+                magnitude_law: MagnitudeLaw::LinearAmplitude,
+                ..Default::default()
+            };
+            let out = render(&a, &b, 1.0, &settings).0;
+            out[8192..].to_vec()
+        };
+
+        // B's formants on A's pitch: A's harmonics, rolling off like B's
+        let out = render_cross(1.0, 0.0);
+        let (fundamental, fourth) = (amplitude(&out, F0_A), amplitude(&out, 4.0 * F0_A));
+        assert!(fundamental > 5.0 * amplitude(&out, F0_B), "pitch isn't A's");
+        assert!(fourth < 0.3 * fundamental, "timbre isn't B's");
+
+        // A's formants on B's pitch: B's harmonics, flattened out like A's
+        let out = render_cross(0.0, 1.0);
+        let (fundamental, tenth) = (amplitude(&out, F0_B), amplitude(&out, 10.0 * F0_B));
+        assert!(fundamental > 5.0 * amplitude(&out, F0_A), "pitch isn't B's");
+        assert!(tenth > 0.3 * fundamental, "timbre isn't A's");
+    }
+
+    #[test]
+    fn morpher_vocoder_follows_sidechain() {
+        // a steady carrier, with B loud for a while, then 60 dB down
+        const F0: f32 = 0.0731;
+        let a = harmonics(32768, F0, |_| 0.1);
+        let b: Vec<f32> = sines(32768, &[0.05, 0.11, 0.2])
+            .into_iter()
+            .enumerate()
+            .map(|(i, x)| if i < 16384 { x } else { x * 1e-3 })
+            .collect();
+        let settings = MorphSettings {
+            combine: CombineMode::Vocoder(VOCODER),
+            ..Default::default()
+        };
+        let (out, latency) = render(&a, &b, 1.0, &settings);
+        let (loud, quiet) = (&out[8192 + latency..16384], &out[24576 + latency..32768]);
+        assert!(
+            rms(loud) > 300.0 * rms(quiet),
+            "{} vs {}",
+            rms(loud),
+            rms(quiet)
+        );
+        // and it's the carrier's pitch that comes through
+        assert!(amplitude(loud, F0) > 5.0 * amplitude(loud, 0.05));
+    }
+
+    #[test]
+    fn morpher_silent_modulator_fades_out() {
+        // B shaping A goes silent for good, fully morphed, with A through it
+        // being the default fallback
+        let a = harmonics(32768, 0.0731, |_| 0.1);
+        let b: Vec<f32> = sines(32768, &[0.05, 0.11, 0.2])
+            .into_iter()
+            .enumerate()
+            .map(|(i, x)| if i < 16384 { x } else { 0.0 })
+            .collect();
+        let settings = |combine| MorphSettings {
+            combine,
+            ..Default::default()
+        };
+
+        // the vocoder's levels fall away as they would for a quiet B
+        let (out, _) = render(&a, &b, 1.0, &settings(CombineMode::Vocoder(VOCODER)));
+        let level = |at: usize| rms(&out[at..at + 1024]);
+        let active = (8192..16384).step_by(1024).map(level).fold(0.0, f32::max);
+        for at in (16384..out.len() - 1024).step_by(256) {
+            assert!(level(at) <= active, "at {at}: {} vs {active}", level(at));
+        }
+        assert!(rms(&out[28672..]) < 1e-3 * active);
+
+        // and there's nothing left to filter A with
+        for normalize in [false, true] {
+            let combine = CombineMode::Convolution {
+                whitening: 0.5,
+                normalize,
+            };
+            let (out, _) = render(&a, &b, 1.0, &settings(combine));
+            assert_eq!(rms(&out[24576..]), 0.0, "{combine:?}");
+        }
+    }
+
+    #[test]
+    fn morpher_convolution_level_independent_of_window_size() {
+        // B filters A by what it has in common with it, at the same level
+        // however long the window it's measured over
+        let gains = |a: &[f32], b: &[f32], whitening| {
+            let settings = MorphSettings {
+                combine: CombineMode::Convolution {
+                    whitening,
+                    normalize: false,
+                },
+                ..Default::default()
+            };
+            [512, 4096].map(|window_size| {
+                let mut morpher = Morpher::new();
+                morpher.configure(AnalysisConfig {
+                    window_size,
+                    hop_length: window_size / 4,
+                    ..Default::default()
+                });
+                let hop_length = morpher.hop_length();
+                let k_morph = vec![1.0; hop_length];
+                let mut out = vec![(0.0, 0.0); a.len()];
+                for ((a, b), out) in a
+                    .chunks_exact(hop_length)
+                    .zip(b.chunks_exact(hop_length))
+                    .zip(out.chunks_exact_mut(hop_length))
+                {
+                    morpher.morph(a, b, &k_morph, &settings, out);
+                }
+                let out: Vec<f32> = out[16384..].iter().map(|(a_to_b, _)| *a_to_b).collect();
+                rms(&out) / rms(&a[16384..])
+            })
+        };
+
+        // a full-scale sine passes a sine at its own level in the peak bin
+        //// and a bit under overall, for the window's main lobe narrowing as it's squared
+        let sine = sines(32768, &[0.2]);
+        let [short, long] = gains(&sine, &sine, 0.0);
+        assert!(short > 0.5 && short < 1.0, "gain {short}");
+        assert!((long / short - 1.0).abs() < 0.1, "{short} vs {long}");
+
+        // and whitened, B's envelope is taken relative to a full-scale one
+        let a = harmonics(32768, 0.0731, |_| 0.1);
+        let b = harmonics(32768, 0.0731, |f| 0.1 / f);
+        for whitening in [0.0, 0.5, 1.0] {
+            let [short, long] = gains(&a, &b, whitening);
+            assert!(
+                (long / short - 1.0).abs() < 0.1,
+                "whitening {whitening}: {short} vs {long}"
             );
-            //// for B -> A morph
-            self.proc_buf.1[i] = Complex32::from_polar(
-                // k.lerp(self.mag_faded[i].1, self.mag_faded[i].0), !!!!!!!!!!!!!!!!!!!!!!!
-                mag.1.powf((1.0 - k_morph).sqrt()) * mag.0.powf(k_morph.sqrt()),
-                self.phase_accum[i].1,
+        }
+    }
+
+    #[test]
+    fn morpher_convolution_filters_a_through_b() {
+        // only what A and B have in common comes through
+        let a = sines(16384, &[0.2, 0.7]);
+        let b = sines(16384, &[0.2]);
+        let settings = MorphSettings {
+            combine: CombineMode::Convolution {
+                whitening: 0.0,
+                normalize: true,
+            },
+            ..Default::default()
+        };
+        let (out, _) = render(&a, &b, 1.0, &settings);
+        let out = &out[8192..];
+        assert!(amplitude(out, 0.2) > 100.0 * amplitude(out, 0.7));
+        // normalized, at about A's level, however loud the product is
+        let level = rms(out) / rms(&a[8192..]);
+        assert!((0.7..1.3).contains(&level), "{level}");
+    }
+
+    #[test]
+    fn morpher_branches_mirror_each_other() {
+        // swapping the inputs swaps the branches, to the bit
+        let a = sines(16384, &[0.031, 0.0071]);
+        let b = sines(16384, &[0.05, 0.11]);
+        let k_morph: Vec<f32> = (0..a.len())
+            .map(|i| (i as f32 * 0.0007).sin() * 0.5 + 0.5)
+            .collect();
+        let convolution = CombineMode::Convolution {
+            whitening: 0.5,
+            normalize: true,
+        };
+        for (iter_count, phase_locking, combine) in [
+            (0, PhaseLocking::Off, CombineMode::Blend),
+            (2, PhaseLocking::Scaled, CombineMode::Blend),
+            (0, PhaseLocking::Off, convolution),
+        ] {
+            let settings = MorphSettings {
+                iter_count,
+                combine,
+                phase_locking,
+                ..Default::default()
+            };
+            let (ab, _) = render_branches(&a, &b, &k_morph, &settings);
+            let (ba, _) = render_branches(&b, &a, &k_morph, &settings);
+            let ba_swapped: Vec<_> = ba
+                .into_iter()
+                .map(|(b_to_a, a_to_b)| (a_to_b, b_to_a))
+                .collect();
+            assert!(ab == ba_swapped, "{settings:?}");
+        }
+    }
+
+    #[test]
+    fn morpher_phase_reset_rules() {
+        use PhaseReset::{Keep, ToA, ToB};
+
+        let mut morpher = Morpher::new();
+        let n_bins = morpher.n_bins;
+        // from silence into the same level, so any share of it is an onset
+        let mut onsets = |a: bool, b: bool, k_morph: f32| {
+            morpher.onset_detectors.0.clear(n_bins);
+            morpher.onset_detectors.1.clear(n_bins);
+            for (detector, onset) in [
+                (&mut morpher.onset_detectors.0, a),
+                (&mut morpher.onset_detectors.1, b),
+            ] {
+                detector.push(&vec![1.0; n_bins]);
+                if onset {
+                    detector.clear(n_bins);
+                }
+                detector.push(&vec![1.0; n_bins]);
+            }
+            morpher.find_phase_resets(k_morph, 1.0, ResetScope::PerBin);
+            morpher.phase_resets[0]
+        };
+
+        assert_eq!(onsets(false, false, 0.5), (Keep, Keep));
+        // an onset resets a branch to its input's phase...
+        assert_eq!(onsets(true, false, 0.3), (ToA, ToA));
+        assert_eq!(onsets(false, true, 0.3), (ToB, ToB));
+        // ...unless it has no part in the branch
+        assert_eq!(onsets(true, false, 1.0), (Keep, ToA));
+        assert_eq!(onsets(false, true, 1.0), (ToB, Keep));
+        assert_eq!(onsets(false, true, 0.0), (Keep, ToB));
+        // with both, the bigger part of the mix wins, the side being morphed from on a tie
+        assert_eq!(onsets(true, true, 0.3), (ToA, ToB));
+        assert_eq!(onsets(true, true, 0.7), (ToB, ToA));
+        assert_eq!(onsets(true, true, 0.5), (ToA, ToB));
+    }
+
+    #[test]
+    fn morpher_transient_takes_its_inputs_phase() {
+        // A comes in out of silence over a steady B; the branch mostly made
+        // of A picks up A's phase right away, rather than carrying on from
+        // whatever it had accumulated while A was silent.
+        const ONSET_AT: usize = 8192;
+        let mut a = vec![0.0; ONSET_AT];
+        a.extend(sines(8192, &[0.0731]));
+        let b = sines(a.len(), &[0.05]);
+        // linear, so A's bins aren't pulled down by B's silence in them
+        let settings = MorphSettings {
+            magnitude_law: MagnitudeLaw::LinearAmplitude,
+            ..Default::default()
+        };
+        let (out, latency) = render(&a, &b, 0.2, &settings);
+
+        let range = ONSET_AT + latency + 1024..ONSET_AT + latency + 2048;
+        let dot: f32 = range.clone().map(|i| out[i] * a[i - latency]).sum();
+        let norm_a: f32 = range.clone().map(|i| a[i - latency].powi(2)).sum();
+        // A's part of the output, projected onto A: in phase, at A's level
+        let gain = dot / norm_a;
+        assert!(gain > 0.6, "A comes out at {gain} of its level, in phase");
+    }
+
+    #[test]
+    fn morpher_onsets_from_silence() {
+        // bins coming out of digital silence are onsets, not a divide by zero
+        let mut a = vec![0.0; 4096];
+        a.extend(sines(12288, &[0.031, 0.0071]));
+        let b = vec![0.0; a.len()];
+        for reset_scope in [ResetScope::PerBin, ResetScope::WholeFrame] {
+            for onset_sensitivity in [0.0, 0.5, 1.0] {
+                let settings = MorphSettings {
+                    onset_sensitivity,
+                    reset_scope,
+                    ..Default::default()
+                };
+                let (out, latency) = render(&a, &b, 0.0, &settings);
+                for i in latency..out.len() {
+                    assert!(
+                        (out[i] - a[i - latency]).abs() < 1e-3,
+                        "{settings:?}, sample {i}"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn morpher_sidechain_fallbacks() {
+        let a = sines(32768, &[0.031]);
+        let mut b = sines(16384, &[0.2]);
+        b.resize(a.len(), 0.0);
+        let settings = |sidechain_fallback| MorphSettings {
+            sidechain_fallback,
+            sidechain_hold: 2048,
+            ..Default::default()
+        };
+        // after the hold time, and the fade over a window
+        const TAKEN_OVER: usize = 16384 + 2048 + 1024;
+
+        // A comes through as it is, even fully morphed into B
+        let (out, latency) = render(&a, &b, 1.0, &settings(SidechainFallback::PassThroughA));
+        for i in TAKEN_OVER + latency + 1024..out.len() {
+            assert!((out[i] - a[i - latency]).abs() < 1e-3, "sample {i}");
+        }
+
+        // B keeps sounding as it was
+        let (out, latency) = render(&a, &b, 1.0, &settings(SidechainFallback::HoldB));
+        let live = rms(&out[8192..16384]);
+        let held = rms(&out[TAKEN_OVER + latency + 1024..]);
+        assert!((held / live - 1.0).abs() < 0.1, "live {live}, held {held}");
+
+        // noise fills in for B
+        let (out, latency) = render(&a, &b, 1.0, &settings(SidechainFallback::Noise));
+        let noise = rms(&out[TAKEN_OVER + latency + 1024..]);
+        assert!(noise > 0.01 && noise < 1.0, "noise at {noise}");
+    }
+
+    #[test]
+    fn morpher_sidechain_gap_shorter_than_hold() {
+        // a pause in B, like between words, is B going quiet, not missing
+        let a = sines(32768, &[0.031]);
+        let b: Vec<f32> = sines(32768, &[0.2])
+            .into_iter()
+            .enumerate()
+            .map(|(i, x)| if (16384..19456).contains(&i) { 0.0 } else { x })
+            .collect();
+        let (out, latency) = render(&a, &b, 1.0, &MorphSettings::default());
+        let gap = rms(&out[16384 + latency + 1024..19456 + latency - 1024]);
+        assert!(gap < 0.01 * rms(&a), "gap at {gap}");
+    }
+
+    #[test]
+    fn morpher_matching_frequencies_stay_coherent() {
+        // an off-bin-center sinusoid, identical on both sides, should come
+        // out as the same sinusoid at any morph amount.
+        let a = sines(32768, &[0.1234]);
+        for k_morph in [0.25, 0.5, 0.75] {
+            let (out, latency) = render(&a, &a, k_morph, &MorphSettings::default());
+            let range = 8192..out.len();
+            let dot: f32 = range.clone().map(|i| out[i] * a[i - latency]).sum();
+            let norm_out: f32 = range.clone().map(|i| out[i] * out[i]).sum();
+            let norm_a: f32 = range.map(|i| a[i - latency] * a[i - latency]).sum();
+            let correlation = dot / (norm_out * norm_a).sqrt();
+            assert!(
+                correlation > 0.99,
+                "k = {k_morph}: correlation {correlation}"
             );
         }
+    }
 
-        // output_windowed = real(ifft(reconstructed)) * window_fn / window_size
-        // window_factor_sum += window_fn^2
-        self.fft_inv.process(&mut self.proc_buf.0);
-        self.fft_inv.process(&mut self.proc_buf.1);
-        for i in 0..self.window_size {
-            let (wave, window_factor_sum) = &mut self.output_buf[i as isize];
-            *wave += k_fade.lerp(self.proc_buf.0[i].re, self.proc_buf.1[i].re)
-                * self.window_func[i]
-                / self.window_size as f32;
-            *window_factor_sum += self.window_func[i] * self.window_func[i];
+    #[test]
+    fn morpher_morph_follows_automation() {
+        // B is A at a quarter the level, so the morph only changes the level
+        let a = sines(32768, &[0.031]);
+        let b: Vec<f32> = a.iter().map(|v| v * 0.25).collect();
+
+        // steady automation gives the same result as a constant morph amount
+        let (steady, _) = render_with(&a, &b, &vec![0.4; a.len()], &MorphSettings::default());
+        let (constant, _) = render(&a, &b, 0.4, &MorphSettings::default());
+        assert_eq!(steady, constant);
+
+        // jumping from A to B in the middle of a hop switches right there,
+        // rather than at the next hop or window boundary
+        const STEP_AT: usize = 10100;
+        let k_morph: Vec<f32> = (0..a.len())
+            .map(|i| if i < STEP_AT { 0.0 } else { 1.0 })
+            .collect();
+        let (out, latency) = render_with(&a, &b, &k_morph, &MorphSettings::default());
+        for i in latency + 2048..out.len() {
+            let expected = if i < STEP_AT + latency {
+                a[i - latency]
+            } else {
+                b[i - latency]
+            };
+            assert!(
+                (out[i] - expected).abs() < 1e-3,
+                "sample {i}: {} != {expected}",
+                out[i]
+            );
         }
+    }
+
+    #[test]
+    fn morpher_morph_follows_automation_within_a_hop() {
+        // k_morph goes up to B and back down to A inside a single hop, so
+        // every frame's window starts and ends at A
+        let a = sines(32768, &[0.031]);
+        let b: Vec<f32> = a.iter().map(|v| v * 0.25).collect();
+        const PEAK_FROM: usize = 10240;
+        let k_morph: Vec<f32> = (0..a.len())
+            .map(|i| match i {
+                PEAK_FROM..=10495 => 1.0 - ((i - PEAK_FROM) as f32 / 128.0 - 1.0).abs(),
+                _ => 0.0,
+            })
+            .collect();
+        let (out, latency) = render_with(&a, &b, &k_morph, &MorphSettings::default());
+        for i in latency + 2048..out.len() {
+            let expected = k_morph[i - latency].lerp(a[i - latency], b[i - latency]);
+            assert!(
+                (out[i] - expected).abs() < 1e-3,
+                "sample {i}: {} != {expected}",
+                out[i]
+            );
+        }
+    }
 
-        self.take_outputs()
+    #[test]
+    fn morpher_linked_channels_reset_together() {
+        // the channels have their onsets at different times
+        let burst = |start: usize, i: usize| {
+            if (start..start + 2048).contains(&(i % 8192)) {
+                1.0
+            } else {
+                0.1
+            }
+        };
+        let tone = sines(32768, &[0.031, 0.13]);
+        let left: Vec<f32> = (0..tone.len()).map(|i| tone[i] * burst(0, i)).collect();
+        let right: Vec<f32> = (0..tone.len()).map(|i| tone[i] * burst(4096, i)).collect();
+        let b = sines(tone.len(), &[0.05]);
+        let mid: Vec<f32> = left
+            .iter()
+            .zip(right.iter())
+            .map(|(l, r)| (l + r) / 2.0)
+            .collect();
+
+        for linked in [false, true] {
+            let (mut morpher_l, mut morpher_r) = (Morpher::new(), Morpher::new());
+            let hop_length = morpher_l.hop_length();
+            let k_morph = vec![0.3; hop_length];
+            let mut out = vec![(0.0, 0.0); hop_length];
+            let (mut agreed, mut any_resets) = (true, false);
+            for hop in 0..tone.len() / hop_length {
+                let range = hop * hop_length..(hop + 1) * hop_length;
+                let link = linked.then(|| (&mid[range.clone()], &b[range.clone()]));
+                for (morpher, a) in [(&mut morpher_l, &left), (&mut morpher_r, &right)] {
+                    morpher.morph_linked(
+                        &a[range.clone()],
+                        &b[range.clone()],
+                        link,
+                        &k_morph,
+                        &MorphSettings::default(),
+                        &mut out,
+                    );
+                }
+                agreed &= morpher_l.phase_resets == morpher_r.phase_resets;
+                any_resets |= morpher_l
+                    .phase_resets
+                    .iter()
+                    .any(|&resets| resets != (PhaseReset::Keep, PhaseReset::Keep));
+            }
+            assert!(any_resets);
+            assert_eq!(agreed, linked, "linked: {linked}");
+        }
+    }
+
+    #[test]
+    fn morpher_window_size_for_ms() {
+        for (sample_rate, window_size) in [
+            (44100.0, 1024),
+            (48000.0, 1024),
+            (96000.0, 2048),
+            (192000.0, 4096),
+        ] {
+            assert_eq!(window_size_for_ms(21.3, sample_rate), window_size);
+            // never more than half an octave off
+            for ms in [10.0, 33.3, 50.0, 80.0] {
+                let window_ms = window_size_for_ms(ms, sample_rate) as f32 / sample_rate * 1000.0;
+                let octaves_off = (window_ms / ms).log2().abs();
+                assert!(
+                    octaves_off <= 0.5,
+                    "{ms} ms at {sample_rate} Hz is {window_ms} ms"
+                );
+            }
+        }
+        assert_eq!(window_size_for_ms(0.0, 44100.0), MIN_WINDOW_SIZE);
+        assert_eq!(window_size_for_ms(10000.0, 192000.0), MAX_WINDOW_SIZE);
+    }
+
+    #[test]
+    fn morpher_survives_pathological_input() {
+        // xorshift, so every run throws the same garbage
+        let mut state = 0x1234_5678u32;
+        let mut random = move || {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state
+        };
+        let extremes = [
+            f32::NAN,
+            f32::INFINITY,
+            f32::NEG_INFINITY,
+            f32::MAX,
+            -f32::MAX,
+            1e30,
+            f32::MIN_POSITIVE / 2.0,
+            -1e-40,
+            0.0,
+        ];
+
+        let mut morpher = Morpher::new();
+        let hop_length = morpher.hop_length();
+        let (mut a, mut b) = (vec![0.0; hop_length], vec![0.0; hop_length]);
+        let mut k_morph = vec![0.0; hop_length];
+        let mut out = vec![(0.0, 0.0); hop_length];
+        for hop in 0..4000 {
+            let settings = MorphSettings {
+                aux_spectral_spread: (random() % 3) as f32 * 0.5,
+                iter_count: (random() % 3) as i32,
+                combine: [
+                    CombineMode::Blend,
+                    CombineMode::CrossSynthesis {
+                        timbre: 1.0,
+                        excitation: 0.5,
+                        lifter_order: 30,
+                    },
+                    CombineMode::Vocoder(VocoderSettings {
+                        formant_shift: 0.5,
+                        noise: 0.5,
+                        ..VOCODER
+                    }),
+                    CombineMode::Convolution {
+                        whitening: 0.5,
+                        normalize: true,
+                    },
+                ][random() as usize % 4],
+                magnitude_law: [
+                    MagnitudeLaw::LinearAmplitude,
+                    MagnitudeLaw::LinearPower,
+                    MagnitudeLaw::Geometric,
+                    MagnitudeLaw::Decibel,
+                    MagnitudeLaw::Curve { shape: 0.7 },
+                ][random() as usize % 5],
+                phase_locking: [
+                    PhaseLocking::Off,
+                    PhaseLocking::Identity,
+                    PhaseLocking::Scaled,
+                ][random() as usize % 3],
+                onset_sensitivity: (random() % 3) as f32 * 0.5,
+                reset_scope: [ResetScope::PerBin, ResetScope::WholeFrame][random() as usize % 2],
+                sidechain_fallback: [
+                    SidechainFallback::PassThroughA,
+                    SidechainFallback::HoldB,
+                    SidechainFallback::Noise,
+                ][random() as usize % 3],
+                sidechain_hold: 1024,
+            };
+            // each hop is mostly sane, mostly garbage or silent, per input
+            for input in [&mut a, &mut b] {
+                let garbage_odds = [0, 1, 8, 1000][random() as usize % 4];
+                for (i, value) in input.iter_mut().enumerate() {
+                    *value = match random() % 1000 {
+                        n if n >= garbage_odds => ((hop * hop_length + i) as f32 * 0.03).sin(),
+                        n if n % 2 == 0 => extremes[random() as usize % extremes.len()],
+                        _ => f32::from_bits(random()),
+                    };
+                }
+                if random() % 8 == 0 {
+                    input.fill(0.0);
+                }
+            }
+            let (k_start, k_end) = (
+                random() as f32 / u32::MAX as f32,
+                random() as f32 / u32::MAX as f32,
+            );
+            for (i, k) in k_morph.iter_mut().enumerate() {
+                *k = (i as f32 / hop_length as f32).lerp(k_start, k_end);
+            }
+
+            morpher.morph(&a, &b, &k_morph, &settings, &mut out);
+            assert!(
+                out.iter().all(|(v0, v1)| v0.is_finite() && v1.is_finite()),
+                "hop {hop}, {settings:?}"
+            );
+        }
+        assert!(morpher.recovered_events() > 0);
+
+        // once the input is back to normal, so is the output
+        let a = sines(hop_length * 64, &[0.031]);
+        let b = sines(hop_length * 64, &[0.05]);
+        let k_morph = vec![0.5; hop_length];
+        let mut recovered_events = 0;
+        let mut peak: f32 = 0.0;
+        for (hop, (a, b)) in a
+            .chunks_exact(hop_length)
+            .zip(b.chunks_exact(hop_length))
+            .enumerate()
+        {
+            morpher.morph(a, b, &k_morph, &MorphSettings::default(), &mut out);
+            //// after the garbage has left the window and output_buf
+            if hop == 8 {
+                recovered_events = morpher.recovered_events();
+            }
+            if hop >= 8 {
+                peak = out
+                    .iter()
+                    .fold(peak, |peak, v| peak.max(v.0.abs()).max(v.1.abs()));
+            }
+        }
+        assert_eq!(morpher.recovered_events(), recovered_events);
+        assert!(peak > 0.1 && peak < 4.0, "peak {peak}");
+    }
+
+    #[test]
+    fn morpher_phase_accum_stays_wrapped() {
+        let mut morpher = Morpher::new();
+        let hop_length = morpher.hop_length();
+        let a = sines(hop_length * 2000, &[0.3, 0.01]);
+        let b = sines(hop_length * 2000, &[0.2]);
+        let k_morph = vec![0.3; hop_length];
+        let mut out = vec![(0.0, 0.0); hop_length];
+        for (a, b) in a.chunks_exact(hop_length).zip(b.chunks_exact(hop_length)) {
+            morpher.morph(a, b, &k_morph, &MorphSettings::default(), &mut out);
+        }
+        let pi = std::f32::consts::PI;
+        assert!(morpher
+            .phase_accum
+            .iter()
+            .all(|&(p0, p1)| (-pi..=pi).contains(&p0) && (-pi..=pi).contains(&p1)));
     }
 }
@@ -0,0 +1,94 @@
+use super::rms;
+
+/// Magnitudes of A's and B's spectra multiplied together, which is A
+/// convolved with B: A filtered through B in the A -> B branch, and the
+/// other way around in B -> A.
+///
+/// The filtering input is whitened first, divided by its spectral
+/// `envelopes` raised to `whitening`, so at `1` its overall tone is
+/// flattened out and only its detail is left to filter with. With
+/// `normalize`, each branch is brought back to the level of the input it
+/// filters. Without it, the filter is taken relative to `unit`, what a
+/// full-scale sine peaks at in `mags` once whitened, so a full-scale filter
+/// passes what it filters at its own level, whatever the window size.
+pub fn convolve(
+    mags: (&[f32], &[f32]),
+    envelopes: (&[f32], &[f32]),
+    whitening: f32,
+    normalize: bool,
+    unit: f32,
+    wet: (&mut [f32], &mut [f32]),
+) {
+    for (dry, filter, envelope, wet) in [
+        (mags.0, mags.1, envelopes.1, wet.0),
+        (mags.1, mags.0, envelopes.0, wet.1),
+    ] {
+        // wet = dry * filter / envelope(filter)^whitening / unit
+        for (i, wet) in wet.iter_mut().enumerate() {
+            *wet = dry[i] * filter[i] / envelope[i].powf(whitening) / unit;
+        }
+
+        // wet *= rms(dry) / rms(wet)
+        if normalize {
+            let wet_level = rms(wet);
+            if wet_level > 0.0 {
+                let gain = rms(dry) / wet_level;
+                for wet in wet.iter_mut() {
+                    *wet *= gain;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::convolve;
+
+    fn convolve_a(
+        mags: (&[f32], &[f32]),
+        envelope_b: &[f32],
+        whitening: f32,
+        normalize: bool,
+    ) -> Vec<f32> {
+        let n_bins = mags.0.len();
+        let mut wet = (vec![0.0; n_bins], vec![0.0; n_bins]);
+        let envelope_a = vec![1.0; n_bins];
+        convolve(
+            mags,
+            (&envelope_a, envelope_b),
+            whitening,
+            normalize,
+            1.0,
+            (&mut wet.0, &mut wet.1),
+        );
+        wet.0
+    }
+
+    #[test]
+    fn convolution_multiplies_magnitudes() {
+        let a = [1.0, 2.0, 3.0, 0.0];
+        let b = [4.0, 0.5, 2.0, 7.0];
+        assert_eq!(
+            convolve_a((&a, &b), &[2.0; 4], 0.0, false),
+            [4.0, 1.0, 6.0, 0.0]
+        );
+        // fully whitened against a flat envelope of 2, B filters at half
+        assert_eq!(
+            convolve_a((&a, &b), &[2.0; 4], 1.0, false),
+            [2.0, 0.5, 3.0, 0.0]
+        );
+    }
+
+    #[test]
+    fn convolution_normalized_to_dry_level() {
+        let a = [1.0, 2.0, 3.0, 0.0];
+        let b = [400.0, 50.0, 200.0, 700.0];
+        let wet = convolve_a((&a, &b), &[1.0; 4], 0.0, true);
+        let power = |mags: &[f32]| mags.iter().map(|mag| mag * mag).sum::<f32>();
+        assert!((power(&wet) / power(&a) - 1.0).abs() < 1e-5);
+        // silence stays silent
+        let wet = convolve_a((&[0.0; 4], &b), &[1.0; 4], 0.0, true);
+        assert_eq!(wet, [0.0; 4]);
+    }
+}
@@ -0,0 +1,136 @@
+use realfft::num_complex::Complex32;
+
+use super::{FftPlanFwd, FftPlanInv, Morpher};
+
+/// Magnitudes below this are taken as this in the log spectrum, so silent
+/// bins don't drag the envelope down to minus infinity.
+const MAG_FLOOR: f32 = 1e-9;
+
+/// Finds the smooth spectral envelope of a magnitude spectrum by cepstral
+/// liftering: the log spectrum's slow ripples (the envelope) end up at low
+/// quefrencies and the fast ones (harmonics, noise) at high quefrencies, so
+/// keeping only the lowest few and transforming back leaves the envelope.
+pub struct SpectralEnvelope {
+    cepstrum: Vec<f32>,
+    spectrum: Vec<Complex32>,
+}
+
+impl SpectralEnvelope {
+    pub fn new(max_window_size: usize) -> Self {
+        Self {
+            cepstrum: vec![0.0; max_window_size],
+            spectrum: vec![Complex32::default(); max_window_size / 2 + 1],
+        }
+    }
+
+    /// Fill `envelope` with the envelope of `mags` (bins `0..=nyquist`),
+    /// keeping quefrencies up to `order` samples. Lower orders are smoother;
+    /// to leave a voice's harmonics out, keep it below the pitch period.
+    pub fn extract(
+        &mut self,
+        (fwd, inv): (&FftPlanFwd, &FftPlanInv),
+        fft_scratch: &mut [Complex32],
+        mags: &[f32],
+        order: usize,
+        envelope: &mut [f32],
+    ) {
+        let n_bins = mags.len();
+        let window_size = (n_bins - 1) * 2;
+        let cepstrum = &mut self.cepstrum[..window_size];
+        let spectrum = &mut self.spectrum[..n_bins];
+
+        // cepstrum = irfft(ln(mags))
+        for (bin, &mag) in spectrum.iter_mut().zip(mags) {
+            *bin = Complex32::new(mag.max(MAG_FLOOR).ln(), 0.0);
+        }
+        Morpher::fft_inverse(inv, spectrum, cepstrum, fft_scratch);
+
+        // cepstrum[order + 1..window_size - order] = 0
+        //// the cepstrum of a real spectrum is symmetric, the lifter has to be too
+        if order < window_size / 2 {
+            cepstrum[order + 1..window_size - order].fill(0.0);
+        }
+
+        // envelope = exp(re(rfft(cepstrum)) / window_size)
+        Morpher::fft_forward(fwd, cepstrum, spectrum, fft_scratch);
+        for (envelope, bin) in envelope.iter_mut().zip(spectrum.iter()) {
+            *envelope = (bin.re / window_size as f32).exp();
+        }
+
+        // envelope *= rms(mags / envelope)
+        //// the liftered envelope runs through the log spectrum's average, well below the
+        //// peaks of a sparse spectrum, so it's raised to leave fine structure with unit
+        //// power. That way the envelope carries the level, however dense the spectrum.
+        let fine_power = mags
+            .iter()
+            .zip(envelope.iter())
+            .map(|(mag, envelope)| (mag / envelope).powi(2))
+            .sum::<f32>()
+            / n_bins as f32;
+        let scale = fine_power.sqrt();
+        if scale > 0.0 && scale.is_finite() {
+            for envelope in envelope.iter_mut() {
+                *envelope *= scale;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use realfft::{num_complex::Complex32, RealFftPlanner};
+
+    use super::SpectralEnvelope;
+
+    fn envelope_of(mags: &[f32], order: usize) -> Vec<f32> {
+        let window_size = (mags.len() - 1) * 2;
+        let mut planner = RealFftPlanner::new();
+        let fwd = planner.plan_fft_forward(window_size);
+        let inv = planner.plan_fft_inverse(window_size);
+        let mut scratch =
+            vec![Complex32::default(); fwd.get_scratch_len().max(inv.get_scratch_len())];
+        let mut envelope = vec![0.0; mags.len()];
+        SpectralEnvelope::new(window_size).extract(
+            (&fwd, &inv),
+            &mut scratch,
+            mags,
+            order,
+            &mut envelope,
+        );
+        envelope
+    }
+
+    #[test]
+    fn envelope_of_flat_spectrum_is_flat() {
+        // and at its level
+        let envelope = envelope_of(&[3.0; 513], 20);
+        assert!(envelope.iter().all(|env| (env - 3.0).abs() < 1e-4));
+    }
+
+    #[test]
+    fn envelope_leaves_out_harmonics() {
+        // harmonics every 16 bins (a 64 sample period), under a formant at bin 160
+        let formant = |k: usize| (-((k as f32 - 160.0) / 60.0).powi(2)).exp() + 0.01;
+        let mags: Vec<f32> = (0..513)
+            .map(|k| {
+                if k % 16 == 0 {
+                    formant(k)
+                } else {
+                    1e-3 * formant(k)
+                }
+            })
+            .collect();
+        // well below the period, the envelope is smooth
+        let envelope = envelope_of(&mags, 12);
+        for k in 100..220 {
+            let ripple = envelope[k] / envelope[k + 1];
+            assert!((0.8..1.25).contains(&ripple), "bin {k}: {ripple}");
+        }
+        assert!(envelope[160] > 10.0 * envelope[400]);
+        // with every quefrency kept, it's the spectrum itself
+        let envelope = envelope_of(&mags, 512);
+        for (env, mag) in envelope.iter().zip(&mags) {
+            assert!((env / mag - 1.0).abs() < 1e-3);
+        }
+    }
+}
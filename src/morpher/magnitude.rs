@@ -0,0 +1,100 @@
+use crate::util::lerpable::Lerpable;
+
+/// How magnitudes are interpolated between the two inputs.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MagnitudeLaw {
+    /// Straight line between the amplitudes.
+    LinearAmplitude,
+    /// Straight line between the powers, so uncorrelated material keeps its
+    /// loudness through the morph.
+    LinearPower,
+    /// Straight line between the log magnitudes, i.e. a weighted geometric
+    /// mean. A bin that's silent on one side stays silent until the very
+    /// end.
+    Geometric,
+    /// Straight line between the levels in dB, with silence taken as
+    /// `DB_FLOOR`, so bins fade in from nothing rather than jump.
+    Decibel,
+    /// Linear amplitude, with the morph amount bent by `shape` in `-1..=1`:
+    /// above zero it moves towards the far side early, below zero late.
+    Curve { shape: f32 },
+}
+
+/// The level silence counts as for `MagnitudeLaw::Decibel`.
+const DB_FLOOR: f32 = -240.0;
+
+impl MagnitudeLaw {
+    /// Magnitude `k` of the way from `from` to `to`. Exactly `from` at
+    /// `k = 0` and exactly `to` at `k = 1`.
+    pub fn morph(self, k: f32, from: f32, to: f32) -> f32 {
+        if k <= 0.0 {
+            return from;
+        }
+        if k >= 1.0 {
+            return to;
+        }
+        match self {
+            Self::LinearAmplitude => k.lerp(from, to),
+            Self::LinearPower => k.lerp(from * from, to * to).sqrt(),
+            Self::Geometric => from.powf(1.0 - k) * to.powf(k),
+            Self::Decibel => {
+                let db = |mag: f32| 20.0 * mag.log10().max(DB_FLOOR / 20.0);
+                let db = k.lerp(db(from), db(to));
+                if db <= DB_FLOOR {
+                    0.0
+                } else {
+                    10f32.powf(db / 20.0)
+                }
+            }
+            Self::Curve { shape } => k.powf(4f32.powf(-shape)).lerp(from, to),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::MagnitudeLaw;
+
+    const LAWS: &[MagnitudeLaw] = &[
+        MagnitudeLaw::LinearAmplitude,
+        MagnitudeLaw::LinearPower,
+        MagnitudeLaw::Geometric,
+        MagnitudeLaw::Decibel,
+        MagnitudeLaw::Curve { shape: -1.0 },
+        MagnitudeLaw::Curve { shape: 0.3 },
+    ];
+
+    #[test]
+    fn magnitude_laws_hit_both_ends_exactly() {
+        let mags = [0.0, 1e-30, 0.1234567, 1.0, 3.0, 777.7, 1e20];
+        for &law in LAWS {
+            for &from in &mags {
+                for &to in &mags {
+                    assert_eq!(law.morph(0.0, from, to), from, "{law:?} {from} -> {to}");
+                    assert_eq!(law.morph(1.0, from, to), to, "{law:?} {from} -> {to}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn magnitude_laws_halfway() {
+        let halfway = |law: MagnitudeLaw| law.morph(0.5, 1.0, 4.0);
+        assert_eq!(halfway(MagnitudeLaw::LinearAmplitude), 2.5);
+        assert!((halfway(MagnitudeLaw::LinearPower) - 8.5f32.sqrt()).abs() < 1e-6);
+        assert!((halfway(MagnitudeLaw::Geometric) - 2.0).abs() < 1e-6);
+        assert!((halfway(MagnitudeLaw::Decibel) - 2.0).abs() < 1e-4);
+        assert_eq!(halfway(MagnitudeLaw::Curve { shape: 0.0 }), 2.5);
+        assert!(halfway(MagnitudeLaw::Curve { shape: 0.5 }) > 2.5);
+        assert!(halfway(MagnitudeLaw::Curve { shape: -0.5 }) < 2.5);
+    }
+
+    #[test]
+    fn magnitude_laws_from_silence() {
+        // geometric holds silence right up to the end, dB fades in
+        assert_eq!(MagnitudeLaw::Geometric.morph(0.9, 0.0, 1.0), 0.0);
+        let faded_in = MagnitudeLaw::Decibel.morph(0.99, 0.0, 1.0);
+        assert!(faded_in > 0.0 && faded_in < 1.0, "{faded_in}");
+        assert_eq!(MagnitudeLaw::Decibel.morph(0.5, 0.0, 0.0), 0.0);
+    }
+}
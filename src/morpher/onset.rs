@@ -0,0 +1,152 @@
+use nih_plug::prelude::Enum;
+
+use crate::util::{lerpable::Lerpable, refill};
+
+#[derive(Enum, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ResetScope {
+    /// Only the bins that jumped in level get their phase reset.
+    #[name = "Per Bin"]
+    PerBin,
+    /// An onset anywhere resets the phase of the whole frame.
+    #[name = "Whole Frame"]
+    WholeFrame,
+}
+
+/// What happens to the phase of one bin of one branch this hop.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum PhaseReset {
+    /// Carry on from the previous frame.
+    Keep,
+    /// Start over from A's phase.
+    ToA,
+    /// Start over from B's phase.
+    ToB,
+}
+
+/// How quickly the running average of the spectral flux follows the current
+/// frame, per frame.
+const FLUX_MEAN_DECAY: f32 = 0.9;
+/// Whole-frame onsets need at least this much of the frame's level in flux,
+/// so rounding noise on a steady signal can't trigger them.
+const FLUX_FLOOR: f32 = 0.01;
+
+/// The rise in level an onset needs, for a sensitivity in `0..=1`: 100x at
+/// the least sensitive, down to any rise at all at the most. `None` turns
+/// onset detection off.
+pub fn onset_threshold(sensitivity: f32) -> Option<f32> {
+    (sensitivity > 0.0).then(|| 100f32.powf(1.0 - sensitivity.min(1.0)))
+}
+
+/// Spectral flux onset detection for one input, working on bins `0..=nyquist`.
+pub struct OnsetDetector {
+    mags: Vec<f32>,
+    mags_prev: Vec<f32>,
+    /// Half-wave rectified spectral flux of the current frame.
+    flux: f32,
+    flux_mean: f32,
+    /// `flux_mean` before the current frame went into it.
+    flux_mean_prev: f32,
+    /// Total level of the current frame.
+    level: f32,
+}
+
+impl OnsetDetector {
+    pub fn new(max_bins: usize) -> Self {
+        Self {
+            mags: Vec::with_capacity(max_bins),
+            mags_prev: Vec::with_capacity(max_bins),
+            flux: 0.0,
+            flux_mean: 0.0,
+            flux_mean_prev: 0.0,
+            level: 0.0,
+        }
+    }
+
+    /// Resize for `n_bins` bins and forget the previous frames.
+    pub fn clear(&mut self, n_bins: usize) {
+        refill(&mut self.mags, n_bins, 0.0);
+        refill(&mut self.mags_prev, n_bins, 0.0);
+        self.flux = 0.0;
+        self.flux_mean = 0.0;
+        self.flux_mean_prev = 0.0;
+        self.level = 0.0;
+    }
+
+    /// Take in the magnitude spectrum of the next frame.
+    pub fn push(&mut self, mags: &[f32]) {
+        std::mem::swap(&mut self.mags, &mut self.mags_prev);
+        self.mags.copy_from_slice(mags);
+
+        self.flux = 0.0;
+        self.level = 0.0;
+        for (&mag, &mag_prev) in self.mags.iter().zip(self.mags_prev.iter()) {
+            self.flux += (mag - mag_prev).max(0.0);
+            self.level += mag;
+        }
+        self.flux_mean_prev = self.flux_mean;
+        self.flux_mean = FLUX_MEAN_DECAY.lerp(self.flux, self.flux_mean);
+        if !self.flux_mean.is_finite() {
+            // a frame loud enough to overflow the sums doesn't get to hold
+            // the mean at infinity from then on
+            self.flux_mean = self.flux_mean_prev;
+        }
+    }
+
+    /// Whether bin `i` has an onset in the current frame, with this input's
+    /// level scaled by `weight`.
+    ///
+    /// Levels are only ever compared by multiplying, so a silent previous
+    /// frame counts as an onset rather than dividing by zero.
+    pub fn is_onset(&self, i: usize, weight: f32, threshold: f32, scope: ResetScope) -> bool {
+        match scope {
+            ResetScope::PerBin => weight * self.mags[i] > threshold * self.mags_prev[i],
+            ResetScope::WholeFrame => {
+                weight * self.flux > threshold * self.flux_mean_prev.max(FLUX_FLOOR * self.level)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{onset_threshold, OnsetDetector, ResetScope};
+
+    #[test]
+    fn onset_per_bin() {
+        let threshold = onset_threshold(0.5).unwrap();
+        let mut detector = OnsetDetector::new(8);
+        detector.clear(4);
+        detector.push(&[1.0, 0.0, 1.0, 0.0]);
+        detector.push(&[20.0, 0.0, 2.0, 1e-30]);
+
+        let onsets: Vec<_> = (0..4)
+            .map(|i| detector.is_onset(i, 1.0, threshold, ResetScope::PerBin))
+            .collect();
+        assert_eq!(onsets, [true, false, false, true]);
+        // a bin that's weighted out of the mix can't reset anything
+        assert!(!detector.is_onset(0, 0.0, threshold, ResetScope::PerBin));
+        assert!(!detector.is_onset(0, 0.4, threshold, ResetScope::PerBin));
+    }
+
+    #[test]
+    fn onset_whole_frame() {
+        let threshold = onset_threshold(0.5).unwrap();
+        let mut detector = OnsetDetector::new(8);
+        detector.clear(4);
+        let steady = [1.0, 0.5, 0.25, 0.125];
+        detector.push(&steady);
+        for _ in 0..10 {
+            detector.push(&steady);
+            assert!(!detector.is_onset(0, 1.0, threshold, ResetScope::WholeFrame));
+        }
+        detector.push(&[1.0, 0.5, 8.0, 0.125]);
+        assert!((0..4).all(|i| detector.is_onset(i, 1.0, threshold, ResetScope::WholeFrame)));
+    }
+
+    #[test]
+    fn onset_threshold_range() {
+        assert_eq!(onset_threshold(0.0), None);
+        assert!((onset_threshold(0.5).unwrap() - 10.0).abs() < 1e-4);
+        assert!((onset_threshold(1.0).unwrap() - 1.0).abs() < 1e-6);
+    }
+}
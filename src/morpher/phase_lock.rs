@@ -0,0 +1,202 @@
+use nih_plug::prelude::Enum;
+
+use crate::util::{refill, wrap_phase};
+
+#[derive(Enum, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum PhaseLocking {
+    Off,
+    /// Bins around each spectral peak keep their phase relative to the peak
+    /// (Laroche-Dolson identity phase locking).
+    Identity,
+    /// Like `Identity`, but each peak's phase carries on from the peak it
+    /// moved from in the previous frame, so peaks can glide between bins
+    /// without losing coherence (Laroche-Dolson scaled phase locking).
+    Scaled,
+}
+
+/// Phase locking for one morph branch, working on bins `0..=nyquist`.
+///
+/// The inputs are filled in bin by bin by the morpher, then `lock` rewrites
+/// `phase` in place.
+pub struct PhaseLocker {
+    /// Magnitudes of the morphed spectrum, used to pick peaks.
+    pub mags: Vec<f32>,
+    /// Analysis phase that relative phases around a peak are taken from.
+    pub reference: Vec<f32>,
+    /// Phase advance of each bin over this hop.
+    pub advance: Vec<f32>,
+    /// Synthesis phase of the previous frame.
+    pub phase_prev: Vec<f32>,
+    /// Synthesis phase: the phase vocoder's going in, locked coming out.
+    pub phase: Vec<f32>,
+
+    /// The peak each bin's region of influence belongs to.
+    peak_of: Vec<usize>,
+    peak_of_prev: Vec<usize>,
+    has_peaks_prev: bool,
+}
+
+impl PhaseLocker {
+    /// How many bins a peak may move in one hop and still count as the same
+    /// partial for scaled phase locking.
+    const MAX_PEAK_MOVE: usize = 2;
+
+    pub fn new(max_bins: usize) -> Self {
+        Self {
+            mags: Vec::with_capacity(max_bins),
+            reference: Vec::with_capacity(max_bins),
+            advance: Vec::with_capacity(max_bins),
+            phase_prev: Vec::with_capacity(max_bins),
+            phase: Vec::with_capacity(max_bins),
+
+            peak_of: Vec::with_capacity(max_bins),
+            peak_of_prev: Vec::with_capacity(max_bins),
+            has_peaks_prev: false,
+        }
+    }
+
+    /// Resize for `n_bins` bins and forget the previous frame.
+    pub fn clear(&mut self, n_bins: usize) {
+        refill(&mut self.mags, n_bins, 0.0);
+        refill(&mut self.reference, n_bins, 0.0);
+        refill(&mut self.advance, n_bins, 0.0);
+        refill(&mut self.phase_prev, n_bins, 0.0);
+        refill(&mut self.phase, n_bins, 0.0);
+        refill(&mut self.peak_of, n_bins, 0);
+        refill(&mut self.peak_of_prev, n_bins, 0);
+        self.has_peaks_prev = false;
+    }
+
+    fn is_peak(mags: &[f32], i: usize) -> bool {
+        let mag = mags[i];
+        mag > f32::MIN_POSITIVE
+            && mag > mags[i - 1]
+            && mag >= mags[i + 1]
+            && (i < 2 || mag > mags[i - 2])
+            && (i + 2 >= mags.len() || mag >= mags[i + 2])
+    }
+
+    /// Pick peaks in `mags` and assign every bin to the peak whose region
+    /// it's in, splitting neighbouring regions at the quietest bin between
+    /// the peaks. Returns whether there were any peaks.
+    fn find_regions(&mut self) -> bool {
+        let n_bins = self.mags.len();
+        let mut prev_peak = None;
+        for i in 1..n_bins.saturating_sub(1) {
+            if !Self::is_peak(&self.mags, i) {
+                continue;
+            }
+            match prev_peak {
+                None => self.peak_of[..i].fill(i),
+                Some(prev_peak) => {
+                    let trough = (prev_peak..i)
+                        .min_by(|&a, &b| self.mags[a].total_cmp(&self.mags[b]))
+                        .unwrap_or(i);
+                    self.peak_of[prev_peak..=trough].fill(prev_peak);
+                    self.peak_of[trough + 1..i].fill(i);
+                }
+            }
+            prev_peak = Some(i);
+        }
+        match prev_peak {
+            None => false,
+            Some(last_peak) => {
+                self.peak_of[last_peak..].fill(last_peak);
+                true
+            }
+        }
+    }
+
+    pub fn lock(&mut self, mode: PhaseLocking) {
+        if mode == PhaseLocking::Off {
+            return;
+        }
+        let has_peaks = self.find_regions();
+        if has_peaks {
+            // peaks first: every other bin is locked to one
+            for i in 0..self.phase.len() {
+                if self.peak_of[i] != i {
+                    continue;
+                }
+                if mode == PhaseLocking::Scaled && self.has_peaks_prev {
+                    // A peak that stayed put already has the right phase. One
+                    // whose old peak is still there is a new partial, not a
+                    // moved one.
+                    let peak_prev = self.peak_of_prev[i];
+                    if peak_prev != i
+                        && peak_prev.abs_diff(i) <= Self::MAX_PEAK_MOVE
+                        && self.peak_of[peak_prev] != peak_prev
+                    {
+                        self.phase[i] = wrap_phase(self.phase_prev[peak_prev] + self.advance[i]);
+                    }
+                }
+            }
+            for i in 0..self.phase.len() {
+                let peak = self.peak_of[i];
+                if peak != i {
+                    self.phase[i] =
+                        wrap_phase(self.phase[peak] + self.reference[i] - self.reference[peak]);
+                }
+            }
+        }
+        std::mem::swap(&mut self.peak_of, &mut self.peak_of_prev);
+        self.has_peaks_prev = has_peaks;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{PhaseLocker, PhaseLocking};
+
+    #[test]
+    fn phase_lock_regions() {
+        let mut locker = PhaseLocker::new(16);
+        locker.clear(12);
+        locker
+            .mags
+            .copy_from_slice(&[0.0, 1.0, 3.0, 1.0, 0.5, 0.2, 0.4, 2.0, 5.0, 2.0, 1.0, 0.0]);
+        assert!(locker.find_regions());
+        assert_eq!(locker.peak_of, [2, 2, 2, 2, 2, 2, 8, 8, 8, 8, 8, 8]);
+    }
+
+    #[test]
+    fn phase_lock_identity_keeps_relative_phase() {
+        let mut locker = PhaseLocker::new(16);
+        locker.clear(8);
+        locker
+            .mags
+            .copy_from_slice(&[0.0, 0.5, 1.0, 4.0, 1.0, 0.5, 0.1, 0.0]);
+        for i in 0..8 {
+            locker.reference[i] = 0.1 * i as f32;
+            locker.phase[i] = -1.0 + 0.3 * i as f32;
+        }
+        locker.lock(PhaseLocking::Identity);
+
+        // the peak keeps its own phase...
+        assert!((locker.phase[3] - (-0.1)).abs() < 1e-6);
+        // ...and everything else is offset from it like in the reference
+        for i in 0..8 {
+            let expected = locker.phase[3] + 0.1 * (i as f32 - 3.0);
+            assert!((locker.phase[i] - expected).abs() < 1e-6, "bin {i}");
+        }
+    }
+
+    #[test]
+    fn phase_lock_scaled_follows_moving_peak() {
+        let mut locker = PhaseLocker::new(16);
+        locker.clear(8);
+        locker
+            .mags
+            .copy_from_slice(&[0.0, 0.5, 1.0, 4.0, 1.0, 0.5, 0.1, 0.0]);
+        locker.lock(PhaseLocking::Scaled);
+
+        // the peak moves up a bin
+        locker
+            .mags
+            .copy_from_slice(&[0.0, 0.1, 0.5, 1.0, 4.0, 1.0, 0.5, 0.0]);
+        locker.phase_prev[3] = 0.5;
+        locker.advance[4] = 0.25;
+        locker.lock(PhaseLocking::Scaled);
+        assert!((locker.phase[4] - 0.75).abs() < 1e-6);
+    }
+}
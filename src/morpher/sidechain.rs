@@ -0,0 +1,166 @@
+use nih_plug::prelude::Enum;
+
+/// What to morph with while the sidechain is silent or missing.
+#[derive(Enum, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum SidechainFallback {
+    /// Let A through untouched, whatever the morph and fade amounts. When B
+    /// only shapes A, as a vocoder's modulator or a convolution's filter,
+    /// it's left to go silent instead.
+    #[name = "Pass Through A"]
+    PassThroughA,
+    /// Keep morphing with the last spectrum B had before it went quiet.
+    #[name = "Hold Last B"]
+    HoldB,
+    /// Morph with white noise instead.
+    #[name = "Internal Noise"]
+    Noise,
+}
+
+/// Peak level below which a hop of the sidechain counts as silent, about
+/// -100 dBFS.
+const SILENCE_THRESHOLD: f32 = 1e-5;
+/// Peak level a hop of the sidechain has to get back up to for the fallback
+/// to let go, about -80 dBFS.
+const RELEASE_THRESHOLD: f32 = 1e-4;
+/// Peak level of the internal noise source, about -20 dBFS.
+const NOISE_LEVEL: f32 = 0.1;
+
+/// Decides when the sidechain has been silent for long enough that the
+/// fallback should take over, and fades it in and out. A gap shorter than
+/// the hold time, or a level hovering around the threshold, doesn't flip
+/// between B and the fallback.
+pub struct SilenceGate {
+    /// How long B has been silent for, in samples.
+    silent_for: usize,
+    /// Whether the fallback has taken over.
+    engaged: bool,
+    /// How far the fallback has faded in, `0..=1`.
+    mix: f32,
+}
+
+impl SilenceGate {
+    pub fn new() -> Self {
+        Self {
+            silent_for: 0,
+            engaged: false,
+            mix: 0.0,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        *self = Self::new();
+    }
+
+    /// Take the next hop of B. The fallback takes over once B has been
+    /// silent for `hold` samples, and lets go as soon as B is back above
+    /// `RELEASE_THRESHOLD`. Fills `mix` with how far the fallback has faded
+    /// in at each sample, fading all the way over `fade` samples.
+    pub fn process(&mut self, b: &[f32], hold: usize, fade: usize, mix: &mut [f32]) {
+        let peak = b.iter().fold(0.0f32, |peak, v| peak.max(v.abs()));
+        if peak >= RELEASE_THRESHOLD || (!self.engaged && peak >= SILENCE_THRESHOLD) {
+            self.silent_for = 0;
+            self.engaged = false;
+        } else if peak < SILENCE_THRESHOLD {
+            self.silent_for += b.len();
+            self.engaged |= self.silent_for >= hold;
+        }
+
+        let step = 1.0 / fade.max(1) as f32;
+        for mix in mix.iter_mut() {
+            self.mix = if self.engaged {
+                (self.mix + step).min(1.0)
+            } else {
+                (self.mix - step).max(0.0)
+            };
+            *mix = self.mix;
+        }
+    }
+
+    /// Whether the fallback has taken over, whether or not it's all the way
+    /// faded in yet.
+    pub fn engaged(&self) -> bool {
+        self.engaged
+    }
+
+    /// Whether B is sounding, with the fallback all the way faded out.
+    pub fn open(&self) -> bool {
+        !self.engaged && self.silent_for == 0 && self.mix == 0.0
+    }
+}
+
+/// White noise from a xorshift generator, cheap and allocation-free.
+pub struct Noise {
+    state: u32,
+}
+
+impl Noise {
+    pub fn new() -> Self {
+        Self { state: 0x9E37_79B9 }
+    }
+
+    pub fn next(&mut self) -> f32 {
+        self.next_uniform() * NOISE_LEVEL
+    }
+
+    /// Uniform in `-1..=1`, rather than at `NOISE_LEVEL`.
+    pub fn next_uniform(&mut self) -> f32 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 17;
+        self.state ^= self.state << 5;
+        self.state as f32 / u32::MAX as f32 * 2.0 - 1.0
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::SilenceGate;
+
+    /// Run hops of `level` through `gate`, returning the last hop's mix.
+    fn hops(gate: &mut SilenceGate, level: f32, n_hops: usize) -> Vec<f32> {
+        let mut mix = vec![0.0; 256];
+        for _ in 0..n_hops {
+            gate.process(&[level; 256], 1024, 512, &mut mix);
+        }
+        mix
+    }
+
+    #[test]
+    fn sidechain_gate_holds_then_fades() {
+        let mut gate = SilenceGate::new();
+        // a gap shorter than the hold time is left alone
+        hops(&mut gate, 0.0, 3);
+        assert!(!gate.engaged());
+        hops(&mut gate, 0.5, 1);
+        hops(&mut gate, 0.0, 3);
+        assert!(!gate.engaged());
+        // a longer one fades the fallback in over two hops
+        let mix = hops(&mut gate, 0.0, 1);
+        assert!(gate.engaged());
+        assert!((mix[0] - 1.0 / 512.0).abs() < 1e-6);
+        assert!((mix[255] - 0.5).abs() < 1e-6);
+        assert_eq!(hops(&mut gate, 0.0, 1)[255], 1.0);
+        // and back out again once B's back
+        let mix = hops(&mut gate, 0.5, 1);
+        assert!(!gate.engaged());
+        assert!((mix[255] - 0.5).abs() < 1e-6);
+        assert_eq!(hops(&mut gate, 0.5, 1)[255], 0.0);
+        assert!(gate.open());
+    }
+
+    #[test]
+    fn sidechain_gate_hysteresis() {
+        let mut gate = SilenceGate::new();
+        hops(&mut gate, 0.0, 4);
+        assert!(gate.engaged());
+        // hovering just over the silence threshold doesn't let go
+        hops(&mut gate, 3e-5, 4);
+        assert!(gate.engaged());
+        hops(&mut gate, 3e-4, 1);
+        assert!(!gate.engaged());
+        // but it does count as sound until the fallback's taken over
+        hops(&mut gate, 0.0, 3);
+        hops(&mut gate, 3e-5, 1);
+        hops(&mut gate, 0.0, 3);
+        assert!(!gate.engaged());
+    }
+}
@@ -0,0 +1,86 @@
+/// Smears a magnitude spectrum across neighbouring bins with an
+/// approximately Gaussian kernel in log-frequency.
+///
+/// The kernel is three passes of a box filter whose span is a fixed ratio of
+/// each bin's frequency, so its width in octaves doesn't depend on the sample
+/// rate or the window size. Each pass is two lookups into prefix sums,
+/// keeping the whole thing linear in the number of bins.
+pub struct SpectralSpread {
+    prefix: Vec<f64>,
+    prefix_weight: Vec<f64>,
+}
+
+impl SpectralSpread {
+    /// Box passes used to approximate the Gaussian.
+    const N_PASSES: usize = 3;
+
+    pub fn new(max_bins: usize) -> Self {
+        Self {
+            prefix: vec![0.0; max_bins + 1],
+            prefix_weight: vec![0.0; max_bins + 1],
+        }
+    }
+
+    /// Blur `mags` (bins `0..=nyquist`) in place with standard deviation
+    /// `sigma` octaves. The DC bin is left alone.
+    pub fn apply(&mut self, mags: &mut [f32], sigma: f32) {
+        let n_bins = mags.len();
+        debug_assert!(n_bins < self.prefix.len());
+        if sigma <= 0.0 || n_bins < 3 {
+            return;
+        }
+        // Each box pass has variance `h^2 / 3` for half-width `h`, so three
+        // passes add up to `sigma^2` when `h = sigma`.
+        let half_width_ratio = 2.0f32.powf(sigma);
+
+        for _ in 0..Self::N_PASSES {
+            // Weight bins by `1/k`, the width each one covers in log-frequency.
+            self.prefix[1] = 0.0;
+            self.prefix_weight[1] = 0.0;
+            for (k, &mag) in mags.iter().enumerate().skip(1) {
+                let weight = 1.0 / k as f64;
+                self.prefix[k + 1] = self.prefix[k] + mag as f64 * weight;
+                self.prefix_weight[k + 1] = self.prefix_weight[k] + weight;
+            }
+            for (k, mag) in mags.iter_mut().enumerate().skip(1) {
+                let lo = ((k as f32 / half_width_ratio).round() as usize).max(1);
+                let hi = ((k as f32 * half_width_ratio).round() as usize).min(n_bins - 1);
+                *mag = ((self.prefix[hi + 1] - self.prefix[lo])
+                    / (self.prefix_weight[hi + 1] - self.prefix_weight[lo]))
+                    as f32;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::SpectralSpread;
+
+    #[test]
+    fn spread_zero_is_identity() {
+        let mut spread = SpectralSpread::new(513);
+        let original: Vec<f32> = (0..513).map(|k| (k % 7) as f32).collect();
+        let mut mags = original.clone();
+        spread.apply(&mut mags, 0.0);
+        assert_eq!(mags, original);
+    }
+
+    #[test]
+    fn spread_smears_peaks_and_keeps_flat_spectra() {
+        let mut spread = SpectralSpread::new(513);
+
+        let mut flat = vec![2.0; 513];
+        spread.apply(&mut flat, 1.0);
+        assert!(flat.iter().all(|mag| (mag - 2.0).abs() < 1e-4));
+
+        let mut peak = vec![0.0; 513];
+        peak[200] = 1.0;
+        spread.apply(&mut peak, 0.5);
+        assert!(peak[200] < 1.0);
+        // half an octave either side still gets a share
+        assert!(peak[141] > 0.0 && peak[283] > 0.0);
+        // but two octaves away doesn't
+        assert_eq!(peak[50], 0.0);
+    }
+}
@@ -0,0 +1,255 @@
+use realfft::num_complex::Complex32;
+
+use super::{rms, sidechain::Noise};
+
+/// Most bands the vocoder splits the spectrum into.
+pub const MAX_BANDS: usize = 64;
+/// Powers below this are taken as this when measuring flatness.
+const POWER_FLOOR: f32 = 1e-18;
+
+/// Settings for `CombineMode::Vocoder`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct VocoderSettings {
+    /// How many bands, log-spaced over `freq_range`.
+    pub bands: usize,
+    /// Lowest and highest band edges, in cycles per sample.
+    pub freq_range: (f32, f32),
+    /// How quickly each band's level follows the modulator as it rises and
+    /// falls: the time to get `1 - 1/e` of the way there, in samples.
+    pub attack: f32,
+    pub release: f32,
+    /// How far up the modulator's formants are moved, in octaves.
+    pub formant_shift: f32,
+    /// How much noise goes into the bands while the modulator sounds like
+    /// noise rather than pitch, for consonants the carrier can't make.
+    /// `0..=1`.
+    pub noise: f32,
+}
+
+/// A channel vocoder working on the morpher's STFT frames: the carrier's
+/// spectrum, split into bands, each scaled by the level of the same band of
+/// the modulator. The carrier is normalized as a whole rather than band by
+/// band, so bands it has nothing in stay empty, and a flat carrier takes on
+/// the modulator's band levels exactly.
+pub struct Vocoder {
+    /// Each band's level, following the modulator: B's in the A -> B
+    /// branch, A's in B -> A.
+    levels: (Vec<f32>, Vec<f32>),
+}
+
+impl Vocoder {
+    pub fn new() -> Self {
+        Self {
+            levels: (vec![0.0; MAX_BANDS], vec![0.0; MAX_BANDS]),
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.levels.0.fill(0.0);
+        self.levels.1.fill(0.0);
+    }
+
+    /// Vocode the next frame of magnitudes `mags` (bins `0..=nyquist`) of
+    /// (A, B), `hop_length` samples on from the last. The A -> B branch is A
+    /// carrying B's bands and B -> A the other way around. Writes each
+    /// branch's magnitudes into `vocoded`, and the noise to add on top into
+    /// `noise_bins`.
+    pub fn process(
+        &mut self,
+        settings: &VocoderSettings,
+        hop_length: usize,
+        mags: (&[f32], &[f32]),
+        vocoded: (&mut [f32], &mut [f32]),
+        noise_bins: (&mut [Complex32], &mut [Complex32]),
+        noise: &mut Noise,
+    ) {
+        let n_bins = mags.0.len();
+        let window_size = (n_bins - 1) * 2;
+        let bands = settings.bands.clamp(1, MAX_BANDS);
+        let low = settings.freq_range.0.clamp(1.0 / window_size as f32, 0.5);
+        let high = settings.freq_range.1.clamp(low, 0.5);
+
+        // edge(j) = low * (high / low)^(j / bands) * 2^shift   [bins]
+        //// every band gets at least one bin, narrow ones at the bottom share
+        let band_bins = |j: usize, shift: f32| {
+            let edge = |j: usize| {
+                low * (high / low).powf(j as f32 / bands as f32)
+                    * 2f32.powf(shift)
+                    * window_size as f32
+            };
+            let start = (edge(j).round() as usize).min(n_bins - 1);
+            let end = (edge(j + 1).round() as usize).clamp(start + 1, n_bins);
+            start..end
+        };
+        let follow = |time: f32| {
+            if time > 0.0 {
+                1.0 - (-(hop_length as f32) / time).exp()
+            } else {
+                1.0
+            }
+        };
+        let (attack, release) = (follow(settings.attack), follow(settings.release));
+        //// formants move up by reading the modulator further down
+        let modulator_shift = -settings.formant_shift;
+
+        for (carrier, modulator, levels, vocoded, noise_bins) in [
+            (mags.0, mags.1, &mut self.levels.0, vocoded.0, noise_bins.0),
+            (mags.1, mags.0, &mut self.levels.1, vocoded.1, noise_bins.1),
+        ] {
+            vocoded.fill(0.0);
+            noise_bins.fill(Complex32::default());
+
+            // unvoiced = flatness(modulator) = geomean(power) / mean(power)
+            //// near 1 for noise, near 0 for a handful of harmonics
+            let modulator_range =
+                band_bins(0, modulator_shift).start..band_bins(bands - 1, modulator_shift).end;
+            let powers = modulator[modulator_range]
+                .iter()
+                .map(|mag| (mag * mag).max(POWER_FLOOR));
+            let n_powers = powers.len() as f32;
+            let (log_sum, sum) = powers.fold((0.0, 0.0), |(log_sum, sum), power| {
+                (log_sum + power.ln(), sum + power)
+            });
+            let unvoiced = ((log_sum / n_powers).exp() / (sum / n_powers)).min(1.0);
+
+            // gain = 1 / rms(carrier)
+            let carrier_level =
+                rms(&carrier[band_bins(0, 0.0).start..band_bins(bands - 1, 0.0).end]);
+            let gain = if carrier_level > 0.0 {
+                1.0 / carrier_level
+            } else {
+                0.0
+            };
+            for (j, level) in levels.iter_mut().enumerate().take(bands) {
+                // level = follow<attack, release>(rms(modulator band))
+                let target = rms(&modulator[band_bins(j, modulator_shift)]);
+                let rate = if target > *level { attack } else { release };
+                *level += (target - *level) * rate;
+
+                // vocoded = carrier * gain * level
+                let noise_level = settings.noise * unvoiced * *level;
+                for i in band_bins(j, 0.0) {
+                    vocoded[i] = carrier[i] * gain * *level;
+                    let phase = noise.next_uniform() * std::f32::consts::PI;
+                    noise_bins[i] = Complex32::from_polar(noise_level, phase);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use realfft::num_complex::Complex32;
+
+    use super::{Vocoder, VocoderSettings};
+    use crate::morpher::sidechain::Noise;
+
+    const SETTINGS: VocoderSettings = VocoderSettings {
+        bands: 16,
+        freq_range: (0.005, 0.4),
+        attack: 0.0,
+        release: 0.0,
+        formant_shift: 0.0,
+        noise: 0.0,
+    };
+
+    /// Vocode `frames` of (A, B) magnitudes, returning the A -> B branch's
+    /// last frame and its noise.
+    fn vocode(settings: &VocoderSettings, frames: &[(Vec<f32>, Vec<f32>)]) -> (Vec<f32>, Vec<f32>) {
+        let n_bins = frames[0].0.len();
+        let mut vocoder = Vocoder::new();
+        let mut vocoded = (vec![0.0; n_bins], vec![0.0; n_bins]);
+        let mut noise_bins = (
+            vec![Complex32::default(); n_bins],
+            vec![Complex32::default(); n_bins],
+        );
+        for (a, b) in frames {
+            vocoder.process(
+                settings,
+                256,
+                (a, b),
+                (&mut vocoded.0, &mut vocoded.1),
+                (&mut noise_bins.0, &mut noise_bins.1),
+                &mut Noise::new(),
+            );
+        }
+        (
+            vocoded.0,
+            noise_bins.0.iter().map(|bin| bin.norm()).collect(),
+        )
+    }
+
+    #[test]
+    fn vocoder_takes_band_levels_from_modulator() {
+        // a flat carrier takes on the modulator's band levels, and nothing
+        // outside the range
+        let carrier = vec![1.0; 513];
+        let modulator: Vec<f32> = (0..513).map(|k| if k < 100 { 4.0 } else { 0.5 }).collect();
+        let (vocoded, _) = vocode(&SETTINGS, &[(carrier, modulator)]);
+        assert!((vocoded[30] - 4.0).abs() < 1e-4);
+        assert!((vocoded[300] - 0.5).abs() < 1e-4);
+        assert_eq!(vocoded[1], 0.0);
+        assert_eq!(vocoded[512], 0.0);
+    }
+
+    #[test]
+    fn vocoder_keeps_carrier_detail() {
+        // within a band, the carrier's shape stays
+        let carrier: Vec<f32> = (0..513).map(|k| (k % 2) as f32).collect();
+        let modulator = vec![1.0; 513];
+        let (vocoded, _) = vocode(&SETTINGS, &[(carrier, modulator)]);
+        assert_eq!(vocoded[300], 0.0);
+        assert!((vocoded[301] - 2f32.sqrt()).abs() < 0.1);
+    }
+
+    #[test]
+    fn vocoder_formant_shift() {
+        // an octave up, the modulator's step at bin 100 lands on bin 200
+        let carrier = vec![1.0; 513];
+        let modulator: Vec<f32> = (0..513).map(|k| if k < 100 { 4.0 } else { 0.5 }).collect();
+        let settings = VocoderSettings {
+            formant_shift: 1.0,
+            ..SETTINGS
+        };
+        let (vocoded, _) = vocode(&settings, &[(carrier, modulator)]);
+        assert!((vocoded[150] - 4.0).abs() < 1e-4);
+        assert!((vocoded[300] - 0.5).abs() < 1e-4);
+    }
+
+    #[test]
+    fn vocoder_attack_and_release() {
+        let carrier = vec![1.0; 513];
+        let settings = VocoderSettings {
+            attack: 256.0,
+            release: 2560.0,
+            ..SETTINGS
+        };
+        // one hop of attack gets `1 - 1/e` of the way up
+        let (vocoded, _) = vocode(&settings, &[(carrier.clone(), vec![1.0; 513])]);
+        assert!((vocoded[300] - (1.0 - (-1f32).exp())).abs() < 1e-4);
+        // and release is ten times slower on the way down
+        let mut frames = vec![(carrier.clone(), vec![1.0; 513]); 8];
+        frames.push((carrier, vec![0.0; 513]));
+        let (vocoded, _) = vocode(&settings, &frames);
+        assert!(vocoded[300] > 0.85, "{}", vocoded[300]);
+    }
+
+    #[test]
+    fn vocoder_noise_follows_unvoiced_modulator() {
+        let carrier = vec![1.0; 513];
+        let settings = VocoderSettings {
+            noise: 1.0,
+            ..SETTINGS
+        };
+        // a flat spectrum is as unvoiced as it gets
+        let (_, noise) = vocode(&settings, &[(carrier.clone(), vec![1.0; 513])]);
+        assert!((noise[300] - 1.0).abs() < 1e-4);
+        // a few harmonics hardly at all
+        let harmonics = (0..513)
+            .map(|k| if k % 32 == 0 { 1.0 } else { 0.0 })
+            .collect();
+        let (_, noise) = vocode(&settings, &[(carrier, harmonics)]);
+        assert!(noise.iter().all(|&noise| noise < 1e-3));
+    }
+}
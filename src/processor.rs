@@ -1,42 +1,813 @@
-use crate::morpher::Morpher;
+use crate::{
+    morpher::{AnalysisConfig, MorphSettings, Morpher, MAX_WINDOW_SIZE},
+    util::{denormals::ScopedFlushDenormals, lerpable::Lerpable, refill},
+};
 
-pub struct Processor {
+/// The longest hop `Lane` has room for, at the smallest overlap.
+const MAX_HOP_LENGTH: usize = MAX_WINDOW_SIZE / 2;
+/// How many samples of a block the lanes render at a time.
+const CHUNK_LENGTH: usize = 256;
+
+/// A morpher, fed a hop at a time from FIFOs.
+struct Lane {
     morpher: Morpher,
+
+    /// Samples collected from the host since the last hop, along with the
+    /// morph amounts that go with them.
+    fifo_a: Vec<f32>,
+    fifo_b: Vec<f32>,
+    fifo_link: (Vec<f32>, Vec<f32>),
+    fifo_k_morph: Vec<f32>,
+    /// Output of both branches from the last hop, handed back to the host as
+    /// input comes in.
+    fifo_out: Vec<(f32, f32)>,
+    /// How far into the current hop the FIFOs are filled.
+    fifo_pos: usize,
+}
+impl Lane {
+    fn new() -> Self {
+        let mut lane = Self {
+            morpher: Morpher::new(),
+
+            fifo_a: Vec::with_capacity(MAX_HOP_LENGTH),
+            fifo_b: Vec::with_capacity(MAX_HOP_LENGTH),
+            fifo_link: (
+                Vec::with_capacity(MAX_HOP_LENGTH),
+                Vec::with_capacity(MAX_HOP_LENGTH),
+            ),
+            fifo_k_morph: Vec::with_capacity(MAX_HOP_LENGTH),
+            fifo_out: Vec::with_capacity(MAX_HOP_LENGTH),
+            fifo_pos: 0,
+        };
+        lane.clear_fifos();
+        lane
+    }
+
+    fn reset(&mut self) {
+        self.morpher.reset();
+        self.clear_fifos();
+    }
+    fn clear_fifos(&mut self) {
+        let hop_length = self.morpher.hop_length();
+        refill(&mut self.fifo_a, hop_length, 0.0);
+        refill(&mut self.fifo_b, hop_length, 0.0);
+        refill(&mut self.fifo_link.0, hop_length, 0.0);
+        refill(&mut self.fifo_link.1, hop_length, 0.0);
+        refill(&mut self.fifo_k_morph, hop_length, 0.0);
+        refill(&mut self.fifo_out, hop_length, (0.0, 0.0));
+        self.fifo_pos = 0;
+    }
+
+    /// On top of the morpher's own latency, the FIFO holds each sample back
+    /// until its hop is complete, which adds another `hop_length`.
+    fn latency_samples(&self) -> usize {
+        self.morpher.latency_samples() + self.morpher.hop_length()
+    }
+    /// How long after starting from silence the output stops depending on
+    /// what came before: one latency period, plus a window for the first
+    /// frame that's all new input to come out the other end.
+    fn warm_up_length(&self) -> usize {
+        self.latency_samples() + self.morpher.config().window_size
+    }
+
+    /// Feed part of a block in, writing the output of both branches that
+    /// goes with it to `out`, and morph each hop as it fills.
+    fn process(
+        &mut self,
+        a: &[f32],
+        b: &[f32],
+        link: Option<(&[f32], &[f32])>,
+        k_morph: &[f32],
+        settings: &MorphSettings,
+        out: &mut [(f32, f32)],
+    ) {
+        let mut pos = 0;
+        while pos < a.len() {
+            let hop_length = self.morpher.hop_length();
+            let n = (hop_length - self.fifo_pos).min(a.len() - pos);
+            let fifo_range = self.fifo_pos..self.fifo_pos + n;
+            let range = pos..pos + n;
+
+            self.fifo_a[fifo_range.clone()].copy_from_slice(&a[range.clone()]);
+            self.fifo_b[fifo_range.clone()].copy_from_slice(&b[range.clone()]);
+            self.fifo_k_morph[fifo_range.clone()].copy_from_slice(&k_morph[range.clone()]);
+            if let Some((link_a, link_b)) = link {
+                self.fifo_link.0[fifo_range.clone()].copy_from_slice(&link_a[range.clone()]);
+                self.fifo_link.1[fifo_range.clone()].copy_from_slice(&link_b[range.clone()]);
+            }
+            out[range].copy_from_slice(&self.fifo_out[fifo_range]);
+
+            self.fifo_pos += n;
+            pos += n;
+
+            if self.fifo_pos == hop_length {
+                self.fifo_pos = 0;
+                self.morpher.morph_linked(
+                    &self.fifo_a,
+                    &self.fifo_b,
+                    link.map(|_| (&self.fifo_link.0[..], &self.fifo_link.1[..])),
+                    &self.fifo_k_morph,
+                    settings,
+                    &mut self.fifo_out,
+                );
+            }
+        }
+    }
+}
+
+/// Where `Processor` is in switching to a new configuration.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Switch {
+    Idle,
+    /// The next lane is running on the new configuration, this many samples
+    /// short of its output being ready.
+    WarmingUp(usize),
+    /// The output is crossfading to the next lane, this many samples in.
+    Crossfading(usize),
+}
+
+/// Where `Processor` is in starting over from silence, for when what's
+/// coming in stops having anything to do with what came before.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Restart {
+    Idle,
+    /// The output is fading out, this many samples into so many.
+    FadingOut(usize, usize),
+    /// The output's silent, waiting for `Processor::restart`.
+    FadedOut,
+    /// The input is fading in after a restart, this many samples into so many.
+    FadingIn(usize, usize),
+}
+
+pub struct Processor {
+    /// The lane the output comes from.
+    active: Lane,
+    /// The lane switched to, running alongside `active` while a switch is
+    /// under way.
+    next: Lane,
+    switch: Switch,
+    restart: Restart,
+    /// The configuration last asked for, which may not be in place yet.
+    target: AnalysisConfig,
+    /// Output of both lanes for the chunk of the block being processed.
+    out_active: Vec<(f32, f32)>,
+    out_next: Vec<(f32, f32)>,
+}
+impl Default for Processor {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 impl Processor {
     pub fn new() -> Self {
+        let active = Lane::new();
         Self {
-            morpher: Morpher::new(),
+            target: active.morpher.config(),
+            active,
+            next: Lane::new(),
+            switch: Switch::Idle,
+            restart: Restart::Idle,
+            out_active: vec![(0.0, 0.0); CHUNK_LENGTH],
+            out_next: vec![(0.0, 0.0); CHUNK_LENGTH],
+        }
+    }
+
+    /// The configuration in use, or the one being switched to.
+    pub fn config(&self) -> AnalysisConfig {
+        self.target
+    }
+    /// Switch to a new STFT configuration. A second morpher starts on the new
+    /// configuration alongside the old one, which carries on being heard
+    /// until the new one's output has warmed up, and is then crossfaded out
+    /// over a window. The output jumps by the difference in latency over the
+    /// crossfade, but never drops out, and never mixes frames windowed one
+    /// way with frames windowed another.
+    ///
+    /// Whatever's asked for while a switch is under way is switched to once
+    /// it's done, so a parameter that's being dragged only has the windows
+    /// recomputed once per switch, not on every block.
+    pub fn configure(&mut self, config: AnalysisConfig) {
+        self.target = config;
+        if self.switch == Switch::Idle {
+            self.switch_to_target();
+        }
+    }
+    /// Start switching to the target configuration if it's not in place.
+    fn switch_to_target(&mut self) {
+        self.switch = if self.target == self.active.morpher.config() {
+            Switch::Idle
+        } else {
+            self.next.morpher.configure(self.target);
+            self.next.reset();
+            Switch::WarmingUp(self.next.warm_up_length())
+        };
+    }
+    /// Forget all the audio that's gone through, so whatever comes next
+    /// renders the same as it would through a new processor. The target
+    /// configuration goes in right away.
+    pub fn reset(&mut self) {
+        self.active.morpher.configure(self.target);
+        self.active.reset();
+        self.switch = Switch::Idle;
+        self.restart = Restart::Idle;
+    }
+
+    /// Fade the output out over a hop, ahead of a `restart`. The processor
+    /// carries on as before until it's `faded_out`, and is silent from then.
+    pub fn fade_out(&mut self) {
+        if let Restart::Idle | Restart::FadingIn(..) = self.restart {
+            self.restart = Restart::FadingOut(0, self.active.morpher.hop_length());
+        }
+    }
+    /// Whether the output's all faded out after a `fade_out`.
+    pub fn faded_out(&self) -> bool {
+        self.restart == Restart::FadedOut
+    }
+    /// Forget all the audio that's gone through, as `reset` does, and fade
+    /// the input back in over a hop, so the output doesn't start with a jump.
+    pub fn restart(&mut self) {
+        self.reset();
+        self.restart = Restart::FadingIn(0, self.active.morpher.hop_length());
+    }
+
+    /// Total delay through the processor, in samples, as reported to the
+    /// host. That's the old configuration's until the new one can be heard.
+    pub fn latency_samples(&self) -> usize {
+        match self.switch {
+            Switch::Crossfading(_) => self.next.latency_samples(),
+            Switch::Idle | Switch::WarmingUp(_) => self.active.latency_samples(),
         }
     }
 
+    /// Whether the sidechain was silent over the last hop.
+    pub fn sidechain_silent(&self) -> bool {
+        self.active.morpher.sidechain_silent()
+    }
+
+    /// How many NaNs and infinities the morpher has recovered from so far.
+    pub fn recovered_events(&self) -> u64 {
+        self.active.morpher.recovered_events() + self.next.morpher.recovered_events()
+    }
+
+    /// Process a block of any length, running the morpher each time a full
+    /// hop worth of input has been collected.
+    ///
+    /// A comes in on `ch0` and B on `ch1`. Going out, `ch0` has the branches
+    /// crossfaded by `k_fade` sample by sample, and `ch1` has the B -> A
+    /// branch on its own. `k_morph` goes with the input samples.
+    ///
+    /// Denormals are flushed to zero for the duration.
     pub fn process(
         &mut self,
         ch0: &mut [f32],
-        ch1: &[f32],
+        ch1: &mut [f32],
         k_morph: &[f32],
         k_fade: &[f32],
-        aux_spectral_spread: f32,
-        iter_count: i32,
+        settings: &MorphSettings,
+    ) {
+        self.process_linked(ch0, ch1, None, k_morph, k_fade, settings);
+    }
+
+    /// Like `process`, but with onsets detected in the `link` signals
+    /// standing in for A and B, if given, so processors linked to the same
+    /// signals reset their phases together.
+    pub fn process_linked(
+        &mut self,
+        ch0: &mut [f32],
+        ch1: &mut [f32],
+        link: Option<(&[f32], &[f32])>,
+        k_morph: &[f32],
+        k_fade: &[f32],
+        settings: &MorphSettings,
     ) {
         debug_assert_eq!(ch0.len(), ch1.len());
         debug_assert_eq!(ch0.len(), k_morph.len());
-        let hop_length = self.morpher.hop_length();
-        let n_chunks = ch0.len() / hop_length;
-        let overflow = ch0.len() % hop_length;
-        assert!(overflow == 0);
+        debug_assert_eq!(ch0.len(), k_fade.len());
+        let _ftz = ScopedFlushDenormals::enable();
+
+        let mut block_pos = 0;
+        while block_pos < ch0.len() {
+            //// chunks end where the switch or the fade out moves on, so it's sample accurate
+            let n = match (self.switch, self.restart) {
+                (_, Restart::FadingOut(pos, len)) => (len - pos).min(CHUNK_LENGTH),
+                (Switch::WarmingUp(remaining), _) => remaining.min(CHUNK_LENGTH),
+                _ => CHUNK_LENGTH,
+            }
+            .min(ch0.len() - block_pos);
+            let range = block_pos..block_pos + n;
 
-        for n in 0..n_chunks {
-            let range = n * hop_length..(n + 1) * hop_length;
-            let out = self.morpher.morph(
+            match self.restart {
+                Restart::FadedOut => {
+                    ch0[range.clone()].fill(0.0);
+                    ch1[range].fill(0.0);
+                    block_pos += n;
+                    continue;
+                }
+                Restart::FadingIn(pos, len) => {
+                    // input *= (pos + i) / len
+                    for (i, (a, b)) in ch0[range.clone()]
+                        .iter_mut()
+                        .zip(ch1[range.clone()].iter_mut())
+                        .enumerate()
+                    {
+                        let gain = ((pos + i) as f32 / len as f32).min(1.0);
+                        (*a, *b) = (*a * gain, *b * gain);
+                    }
+                    self.restart = match pos + n {
+                        pos if pos < len => Restart::FadingIn(pos, len),
+                        _ => Restart::Idle,
+                    };
+                }
+                Restart::Idle | Restart::FadingOut(..) => {}
+            }
+            let link = link.map(|(a, b)| (&a[range.clone()], &b[range.clone()]));
+            let out_active = &mut self.out_active[..n];
+            let out_next = &mut self.out_next[..n];
+
+            self.active.process(
                 &ch0[range.clone()],
                 &ch1[range.clone()],
-                k_morph[n * hop_length],
-                k_fade[n * hop_length],
-                aux_spectral_spread,
-                iter_count,
+                link,
+                &k_morph[range.clone()],
+                settings,
+                out_active,
+            );
+            if self.switch != Switch::Idle {
+                self.next.process(
+                    &ch0[range.clone()],
+                    &ch1[range.clone()],
+                    link,
+                    &k_morph[range.clone()],
+                    settings,
+                    out_next,
+                );
+            }
+            if let Switch::Crossfading(pos) = self.switch {
+                // out = lerp(active, next, (pos + i) / crossfade_length)
+                let crossfade_length = self.next.morpher.config().window_size;
+                for (i, (wave, next)) in out_active.iter_mut().zip(out_next.iter()).enumerate() {
+                    let k = ((pos + i) as f32 / crossfade_length as f32).min(1.0);
+                    *wave = (k.lerp(wave.0, next.0), k.lerp(wave.1, next.1));
+                }
+            }
+            if let Restart::FadingOut(pos, len) = self.restart {
+                // out *= 1 - (pos + i) / len
+                for (i, wave) in out_active.iter_mut().enumerate() {
+                    let gain = 1.0 - (pos + i) as f32 / len as f32;
+                    *wave = (wave.0 * gain, wave.1 * gain);
+                }
+                self.restart = match pos + n {
+                    pos if pos < len => Restart::FadingOut(pos, len),
+                    _ => Restart::FadedOut,
+                };
+            }
+
+            for ((out, out_b_to_a), (wave, k_fade)) in ch0[range.clone()]
+                .iter_mut()
+                .zip(ch1[range.clone()].iter_mut())
+                .zip(out_active.iter().zip(k_fade[range].iter()))
+            {
+                *out = k_fade.lerp(wave.0, wave.1);
+                *out_b_to_a = wave.1;
+            }
+            block_pos += n;
+
+            self.advance_switch(n);
+        }
+    }
+
+    /// Move the switch on by `n` samples.
+    fn advance_switch(&mut self, n: usize) {
+        self.switch = match self.switch {
+            Switch::Idle => Switch::Idle,
+            Switch::WarmingUp(remaining) if remaining > n => Switch::WarmingUp(remaining - n),
+            //// the target changed while warming up, so this lane is no use
+            Switch::WarmingUp(_) if self.next.morpher.config() != self.target => {
+                self.switch_to_target();
+                return;
+            }
+            Switch::WarmingUp(_) => Switch::Crossfading(0),
+            Switch::Crossfading(pos) if pos + n < self.next.morpher.config().window_size => {
+                Switch::Crossfading(pos + n)
+            }
+            Switch::Crossfading(_) => {
+                std::mem::swap(&mut self.active, &mut self.next);
+                self.switch_to_target();
+                return;
+            }
+        };
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Processor;
+    use crate::{
+        morpher::{
+            window_size_for_ms, AnalysisConfig, MorphSettings, PhaseLocking, SidechainFallback,
+        },
+        util::lerpable::Lerpable,
+        window::WindowFunction,
+    };
+
+    fn render(block_lengths: &[usize], input: &[f32]) -> Vec<f32> {
+        render_with(&mut Processor::new(), block_lengths, input)
+    }
+    fn render_with(processor: &mut Processor, block_lengths: &[usize], input: &[f32]) -> Vec<f32> {
+        let mut output = input.to_vec();
+        let mut b = vec![0.0; input.len()];
+        let silence = vec![0.0; input.len()];
+        let mut pos = 0;
+        for &len in block_lengths.iter().cycle() {
+            if pos >= output.len() {
+                break;
+            }
+            let range = pos..(pos + len).min(output.len());
+            let len = range.len();
+            processor.process(
+                &mut output[range.clone()],
+                &mut b[range],
+                &silence[..len],
+                &silence[..len],
+                &MorphSettings::default(),
+            );
+            pos += len;
+        }
+        output
+    }
+
+    #[test]
+    fn processor_block_size_independent() {
+        let input: Vec<f32> = (0..8192).map(|i| (i as f32 * 0.05).sin()).collect();
+        let reference = render(&[256], &input);
+        for block_lengths in [&[100][..], &[441], &[1], &[37, 512, 3, 1000]] {
+            assert_eq!(
+                render(block_lengths, &input),
+                reference,
+                "block lengths {block_lengths:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn processor_latency_matches_impulse_delay() {
+        const IMPULSE_AT: usize = 100;
+        let mut processor = Processor::new();
+        let latency = processor.latency_samples();
+
+        let mut input = vec![0.0; IMPULSE_AT + latency + 4096];
+        input[IMPULSE_AT] = 1.0;
+        let output = render_with(&mut processor, &[441], &input);
+
+        let (peak_at, peak) = output
+            .iter()
+            .enumerate()
+            .max_by(|(_, a), (_, b)| a.abs().total_cmp(&b.abs()))
+            .unwrap();
+        assert_eq!(peak_at - IMPULSE_AT, latency, "measured delay");
+        assert!((peak - 1.0).abs() < 1e-3, "impulse amplitude {peak}");
+    }
+
+    #[test]
+    fn processor_reset_renders_identically() {
+        let a: Vec<f32> = (0..20000).map(|i| (i as f32 * 0.03).sin()).collect();
+        // with a gap in B for the noise fallback to fill
+        let b: Vec<f32> = (0..a.len())
+            .map(|i| match i {
+                5000..9000 => 0.0,
+                _ => (i as f32 * 0.11).sin(),
+            })
+            .collect();
+        let k: Vec<f32> = (0..a.len()).map(|i| i as f32 / a.len() as f32).collect();
+        let settings = MorphSettings {
+            aux_spectral_spread: 0.5,
+            iter_count: 2,
+            phase_locking: PhaseLocking::Scaled,
+            sidechain_fallback: SidechainFallback::Noise,
+            sidechain_hold: 1024,
+            ..Default::default()
+        };
+        let render = |processor: &mut Processor| {
+            let (mut output, mut b) = (a.clone(), b.clone());
+            // leave the FIFO part way through a hop at the end
+            for start in (0..a.len()).step_by(441) {
+                let range = start..(start + 441).min(a.len());
+                processor.process(
+                    &mut output[range.clone()],
+                    &mut b[range.clone()],
+                    &k[range.clone()],
+                    &k[range],
+                    &settings,
+                );
+            }
+            output
+        };
+
+        let mut processor = Processor::new();
+        let fresh = render(&mut processor);
+        processor.reset();
+        let first = render(&mut processor);
+        processor.reset();
+        let second = render(&mut processor);
+        assert!(
+            first == fresh,
+            "render after reset differs from a fresh one"
+        );
+        assert!(first == second, "renders after reset differ");
+    }
+
+    #[test]
+    fn processor_fade_is_sample_accurate() {
+        // with no morph, the branches are A and B as they are, so the output
+        // is them crossfaded by the fade as it was at the output, and the
+        // B -> A output is B
+        let mut processor = Processor::new();
+        let latency = processor.latency_samples();
+        let a: Vec<f32> = (0..16384).map(|i| (i as f32 * 0.05).sin()).collect();
+        let b: Vec<f32> = (0..a.len()).map(|i| (i as f32 * 0.21).sin()).collect();
+        let k_fade: Vec<f32> = (0..a.len())
+            .map(|i| (i as f32 * 0.013).sin() * 0.5 + 0.5)
+            .collect();
+        let k_morph = vec![0.0; a.len()];
+
+        let (mut output, mut output_b_to_a) = (a.clone(), b.clone());
+        for start in (0..a.len()).step_by(100) {
+            let range = start..(start + 100).min(a.len());
+            processor.process(
+                &mut output[range.clone()],
+                &mut output_b_to_a[range.clone()],
+                &k_morph[range.clone()],
+                &k_fade[range],
+                &MorphSettings::default(),
+            );
+        }
+        for i in latency + 1024..output.len() {
+            let expected = k_fade[i].lerp(a[i - latency], b[i - latency]);
+            assert!(
+                (output[i] - expected).abs() < 1e-3,
+                "sample {i}: {} != {expected}",
+                output[i]
+            );
+            assert!(
+                (output_b_to_a[i] - b[i - latency]).abs() < 1e-3,
+                "B -> A sample {i}"
+            );
+        }
+    }
+
+    /// Only under `cargo test --release`, see `util::allocations`.
+    #[cfg(not(debug_assertions))]
+    #[test]
+    fn processor_does_not_allocate() {
+        use crate::util::allocations::count_allocations;
+
+        let mut processor = Processor::new();
+        let len = 1 << 17;
+        let mut main: Vec<f32> = (0..len).map(|i| (i as f32 * 0.01).sin()).collect();
+        let mut aux: Vec<f32> = (0..len).map(|i| (i as f32 * 0.023).sin()).collect();
+        let k: Vec<f32> = (0..len).map(|i| i as f32 / len as f32).collect();
+        let settings = MorphSettings {
+            aux_spectral_spread: 0.5,
+            iter_count: 2,
+            combine: crate::morpher::CombineMode::CrossSynthesis {
+                timbre: 1.0,
+                excitation: 0.5,
+                lifter_order: 30,
+            },
+            phase_locking: PhaseLocking::Scaled,
+            ..Default::default()
+        };
+        let configs =
+            [(16384, 2), (256, 16), (4096, 8)].map(|(window_size, overlap)| AnalysisConfig {
+                window_size,
+                hop_length: window_size / overlap,
+                window_function: WindowFunction::Blackman,
+            });
+
+        let (_, allocations) = count_allocations(|| {
+            let mut pos = 0;
+            for (i, block_len) in [441, 1, 37, 2048, 512].into_iter().cycle().enumerate() {
+                if pos + block_len > len {
+                    break;
+                }
+                if i % 16 == 15 {
+                    processor.configure(configs[i / 16 % configs.len()]);
+                }
+                let range = pos..pos + block_len;
+                processor.process(
+                    &mut main[range.clone()],
+                    &mut aux[range.clone()],
+                    &k[range.clone()],
+                    &k[range],
+                    &settings,
+                );
+                pos += block_len;
+            }
+        });
+        assert_eq!(allocations, 0);
+    }
+
+    #[test]
+    fn processor_window_ms_across_sample_rates() {
+        // the same window length in ms gives about the same latency in ms
+        // and the same clean pass-through at every sample rate
+        const WINDOW_MS: f32 = 40.0;
+        for sample_rate in [44100.0, 48000.0, 96000.0, 192000.0] {
+            let window_size = window_size_for_ms(WINDOW_MS, sample_rate);
+            let mut processor = Processor::new();
+            processor.configure(AnalysisConfig {
+                window_size,
+                hop_length: window_size / 4,
+                window_function: WindowFunction::Hann,
+            });
+            processor.reset();
+            let latency = processor.latency_samples();
+            let latency_ms = latency as f32 / sample_rate * 1000.0;
+            assert!(
+                (latency_ms / WINDOW_MS).log2().abs() <= 0.5,
+                "{sample_rate} Hz: latency {latency_ms} ms"
+            );
+
+            let len = sample_rate as usize / 2;
+            let omega = std::f32::consts::TAU * 440.0 / sample_rate;
+            let input: Vec<f32> = (0..len).map(|i| (i as f32 * omega).sin()).collect();
+            let output = render_with(&mut processor, &[512], &input);
+            for i in 2 * latency..len {
+                assert!(
+                    (output[i] - input[i - latency]).abs() < 1e-3,
+                    "{sample_rate} Hz, sample {i}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn processor_reconfigure_crossfades() {
+        // switching window size mid-stream keeps the old configuration going
+        // until the new one's warmed up, then crossfades, with no jump bigger
+        // than the sine's own steps plus the crossfade's, and no dropout
+        const SWITCH_AT: usize = 10143;
+        let input: Vec<f32> = (0..60000).map(|i| (i as f32 * 0.01).sin()).collect();
+        let mut processor = Processor::new();
+        let old_latency = processor.latency_samples();
+        let mut output = input.clone();
+        let mut b = vec![0.0; input.len()];
+        let silence = vec![0.0; input.len()];
+        let mut latencies = Vec::new();
+        for start in (0..input.len()).step_by(441) {
+            if start == SWITCH_AT {
+                processor.configure(AnalysisConfig {
+                    window_size: 2048,
+                    hop_length: 512,
+                    window_function: WindowFunction::Hann,
+                });
+            }
+            let range = start..(start + 441).min(input.len());
+            processor.process(
+                &mut output[range.clone()],
+                &mut b[range.clone()],
+                &silence[range.clone()],
+                &silence[range],
+                &MorphSettings::default(),
+            );
+            latencies.push((start, processor.latency_samples()));
+        }
+        for i in 1..output.len() {
+            let step = (output[i] - output[i - 1]).abs();
+            assert!(step < 0.015, "sample {i}: step of {step}");
+        }
+        for (i, chunk) in output[old_latency + 1024..].chunks(700).enumerate() {
+            let peak = chunk.iter().fold(0.0f32, |peak, v| peak.max(v.abs()));
+            assert!(peak > 0.3, "chunk {i}: peak of {peak}");
+        }
+        // the new latency is reported once the new configuration's heard,
+        // not before
+        let new_latency = processor.latency_samples();
+        assert_eq!(new_latency, 2048);
+        let heard_from = SWITCH_AT + 2 * new_latency;
+        for (start, latency) in latencies {
+            let expected = match start + 441 {
+                end if end <= heard_from => old_latency,
+                end if end > heard_from + 441 => new_latency,
+                _ => continue,
+            };
+            assert_eq!(latency, expected, "block at {start}");
+        }
+        for i in heard_from + 2 * new_latency..output.len() {
+            assert!(
+                (output[i] - input[i - new_latency]).abs() < 1e-3,
+                "sample {i}"
+            );
+        }
+    }
+
+    #[test]
+    fn processor_window_shape_change_crossfades() {
+        // with the shape dragged around on every block, the old windows
+        // carry on being heard until the last shape has warmed up, then the
+        // new ones crossfade in
+        const DRAG: std::ops::Range<usize> = 4410..8820;
+        let input: Vec<f32> = (0..30000).map(|i| (i as f32 * 0.01).sin()).collect();
+        let reference = render(&[441], &input);
+        let mut processor = Processor::new();
+        let mut output = input.clone();
+        let mut b = vec![0.0; input.len()];
+        let silence = vec![0.0; input.len()];
+        for start in (0..input.len()).step_by(441) {
+            if DRAG.contains(&start) {
+                processor.configure(AnalysisConfig {
+                    window_function: WindowFunction::Kaiser {
+                        beta: start as f32 * 0.001,
+                    },
+                    ..Default::default()
+                });
+            }
+            let range = start..(start + 441).min(input.len());
+            processor.process(
+                &mut output[range.clone()],
+                &mut b[range.clone()],
+                &silence[range.clone()],
+                &silence[range],
+                &MorphSettings::default(),
+            );
+        }
+        assert!(output[..DRAG.end] == reference[..DRAG.end]);
+        for i in 1..output.len() {
+            let step = (output[i] - output[i - 1]).abs();
+            assert!(step < 0.015, "sample {i}: step of {step}");
+        }
+        let latency = processor.latency_samples();
+        for i in DRAG.end + 4 * latency..output.len() {
+            assert!((output[i] - input[i - latency]).abs() < 1e-3, "sample {i}");
+        }
+    }
+
+    #[test]
+    fn processor_restart_fades() {
+        // fading out, then starting over from silence, never jumps by more
+        // than the sine's own steps plus the fades'
+        const FADE_OUT_AT: usize = 10143;
+        let input: Vec<f32> = (0..30000).map(|i| (i as f32 * 0.01).sin()).collect();
+        let mut processor = Processor::new();
+        let latency = processor.latency_samples();
+        let mut output = input.clone();
+        let mut b = vec![0.0; input.len()];
+        let silence = vec![0.0; input.len()];
+        let mut restarted_at = None;
+        for start in (0..input.len()).step_by(441) {
+            if start >= FADE_OUT_AT && restarted_at.is_none() {
+                processor.fade_out();
+                if processor.faded_out() {
+                    processor.restart();
+                    restarted_at = Some(start);
+                }
+            }
+            let range = start..(start + 441).min(input.len());
+            processor.process(
+                &mut output[range.clone()],
+                &mut b[range.clone()],
+                &silence[range.clone()],
+                &silence[range],
+                &MorphSettings::default(),
+            );
+        }
+        for i in 1..output.len() {
+            let step = (output[i] - output[i - 1]).abs();
+            assert!(step < 0.015, "sample {i}: step of {step}");
+        }
+        let restarted_at = restarted_at.unwrap();
+        assert!(output[FADE_OUT_AT + 256..restarted_at + latency]
+            .iter()
+            .all(|v| v.abs() < 1e-6));
+        for i in restarted_at + 2 * latency..output.len() {
+            assert!((output[i] - input[i - latency]).abs() < 1e-3, "sample {i}");
+        }
+    }
+
+    #[test]
+    fn processor_reconfigure() {
+        let input: Vec<f32> = (0..60000).map(|i| (i as f32 * 0.01).sin()).collect();
+        let mut processor = Processor::new();
+        for (window_size, overlap, window_function) in [
+            (256, 16, WindowFunction::Blackman),
+            (16384, 2, WindowFunction::SqrtHann),
+            (4096, 8, WindowFunction::Kaiser { beta: 8.0 }),
+            (1024, 4, WindowFunction::Hann),
+        ] {
+            let config = AnalysisConfig {
+                window_size,
+                hop_length: window_size / overlap,
+                window_function,
+            };
+            processor.configure(config);
+            assert_eq!(processor.config(), config);
+
+            let output = render_with(&mut processor, &[441], &input);
+            assert!(
+                output.iter().all(|v| v.is_finite() && v.abs() < 2.0),
+                "{window_size}/{overlap}x output blew up"
             );
-            ch0[range].copy_from_slice(&out);
+            assert_eq!(processor.latency_samples(), window_size);
         }
     }
 }
@@ -0,0 +1,103 @@
+//! Matching the sidechain's channels up with the main bus's.
+
+/// Fill the first `len` samples of each of `out`, one per main channel, with
+/// the sidechain mixed to match: the same number of channels map straight
+/// across, a mono sidechain goes to every channel, and otherwise every
+/// channel gets the average of all the sidechain's. No sidechain is silence.
+pub fn mix_sidechain(aux: Option<&[&mut [f32]]>, out: &mut [Vec<f32>], len: usize) {
+    let n_out = out.len();
+    for (channel_id, out) in out.iter_mut().enumerate() {
+        let out = &mut out[..len];
+        match aux {
+            None | Some([]) => out.fill(0.0),
+            Some(aux) if aux.len() == n_out || aux.len() == 1 => {
+                out.copy_from_slice(&aux[channel_id.min(aux.len() - 1)][..len]);
+            }
+            Some(aux) => mix_down(aux, out),
+        }
+    }
+}
+
+/// Fill `out` with the average of `channels`.
+pub fn mix_down<C: AsRef<[f32]>>(channels: &[C], out: &mut [f32]) {
+    out.fill(0.0);
+    let weight = 1.0 / channels.len().max(1) as f32;
+    for channel in channels {
+        for (out, value) in out.iter_mut().zip(channel.as_ref()) {
+            *out += value * weight;
+        }
+    }
+}
+
+/// Turn left and right into mid and side, in place.
+pub fn to_mid_side(left: &mut [f32], right: &mut [f32]) {
+    for (l, r) in left.iter_mut().zip(right.iter_mut()) {
+        (*l, *r) = ((*l + *r) * 0.5, (*l - *r) * 0.5);
+    }
+}
+
+/// Turn mid and side back into left and right, in place.
+pub fn from_mid_side(mid: &mut [f32], side: &mut [f32]) {
+    for (m, s) in mid.iter_mut().zip(side.iter_mut()) {
+        (*m, *s) = (*m + *s, *m - *s);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{from_mid_side, mix_sidechain, to_mid_side};
+
+    /// Mix a sidechain of `aux` channels, each filled with its channel
+    /// number plus one, for `n_main` main channels.
+    fn mix(n_main: usize, aux: Option<usize>) -> Vec<Vec<f32>> {
+        let mut aux: Option<Vec<Vec<f32>>> =
+            aux.map(|n_aux| (0..n_aux).map(|i| vec![i as f32 + 1.0; 8]).collect());
+        let aux_slices: Option<Vec<&mut [f32]>> = aux
+            .as_mut()
+            .map(|aux| aux.iter_mut().map(|ch| &mut ch[..]).collect());
+        let mut out = vec![vec![f32::NAN; 16]; n_main];
+        mix_sidechain(aux_slices.as_deref(), &mut out, 8);
+        out.iter().map(|ch| ch[..8].to_vec()).collect()
+    }
+
+    #[test]
+    fn routing_stereo() {
+        assert_eq!(mix(2, Some(2)), [vec![1.0; 8], vec![2.0; 8]]);
+    }
+
+    #[test]
+    fn routing_mono() {
+        assert_eq!(mix(1, Some(1)), [vec![1.0; 8]]);
+    }
+
+    #[test]
+    fn routing_stereo_main_mono_sidechain() {
+        assert_eq!(mix(2, Some(1)), [vec![1.0; 8], vec![1.0; 8]]);
+    }
+
+    #[test]
+    fn routing_mono_main_stereo_sidechain() {
+        assert_eq!(mix(1, Some(2)), [vec![1.5; 8]]);
+    }
+
+    #[test]
+    fn routing_missing_sidechain() {
+        assert_eq!(mix(2, None), [vec![0.0; 8], vec![0.0; 8]]);
+        assert_eq!(mix(1, Some(0)), [vec![0.0; 8]]);
+    }
+
+    #[test]
+    fn routing_mid_side_round_trip() {
+        let (mut a, mut b) = (vec![1.0, 0.5, -0.25], vec![1.0, -0.5, 0.75]);
+        to_mid_side(&mut a, &mut b);
+        assert_eq!(
+            (&a[..], &b[..]),
+            (&[1.0, 0.0, 0.25][..], &[0.0, 0.5, -0.5][..])
+        );
+        from_mid_side(&mut a, &mut b);
+        assert_eq!(
+            (&a[..], &b[..]),
+            (&[1.0, 0.5, -0.25][..], &[1.0, -0.5, 0.75][..])
+        );
+    }
+}
@@ -1,2 +1,30 @@
+#[cfg(all(test, not(debug_assertions)))]
+pub mod allocations;
+pub mod denormals;
 pub mod lerpable;
-pub mod ring_buffer;
\ No newline at end of file
+pub mod ring_buffer;
+
+/// Clear `vec` and fill it with `len` copies of `value`.
+///
+/// Doesn't allocate as long as `len` fits in the vec's capacity.
+pub fn refill<T: Clone>(vec: &mut Vec<T>, len: usize, value: T) {
+    vec.clear();
+    vec.resize(len, value);
+}
+
+/// Wrap a phase in radians to its principal value, in `[-pi, pi)`.
+pub fn wrap_phase(phase: f32) -> f32 {
+    use std::f32::consts::{PI, TAU};
+    phase - TAU * ((phase + PI) / TAU).floor()
+}
+
+/// Replace any non-finite values in `values` with zero, returning how many
+/// there were.
+pub fn sanitize(values: &mut [f32]) -> usize {
+    let mut count = 0;
+    for value in values.iter_mut().filter(|value| !value.is_finite()) {
+        *value = 0.0;
+        count += 1;
+    }
+    count
+}
@@ -0,0 +1,71 @@
+//! Counting heap allocations, for tests that keep them out of the audio
+//! path.
+//!
+//! nih_plug's `assert_process_allocs` brings its own global allocator in
+//! debug builds and this one would clash with it, so it only exists in
+//! release test builds, i.e. under `cargo test --release`.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    cell::Cell,
+};
+
+thread_local! {
+    /// Allocations on this thread since counting started, if it has.
+    static ALLOCATIONS: Cell<Option<usize>> = const { Cell::new(None) };
+}
+
+struct CountingAllocator;
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+fn count() {
+    let _ = ALLOCATIONS.try_with(|n| n.set(n.get().map(|n| n + 1)));
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        count();
+        System.alloc(layout)
+    }
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        count();
+        System.alloc_zeroed(layout)
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        count();
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+/// Run `f`, returning its result and how many allocations it made on this
+/// thread.
+pub fn count_allocations<T>(f: impl FnOnce() -> T) -> (T, usize) {
+    let outer = ALLOCATIONS.with(|n| n.replace(Some(0)));
+    let result = f();
+    let allocations = ALLOCATIONS.with(|n| n.get()).unwrap_or(0);
+    //// counting inside a count adds to the outer one
+    ALLOCATIONS.with(|n| n.set(outer.map(|outer| outer + allocations)));
+    (result, allocations)
+}
+
+#[cfg(test)]
+mod test {
+    use super::count_allocations;
+
+    #[test]
+    fn allocations_counted() {
+        let (_, allocations) = count_allocations(|| vec![0u8; 16]);
+        assert_eq!(allocations, 1);
+        let (_, allocations) = count_allocations(|| {
+            let (_, inner) = count_allocations(|| vec![0u8; 16]);
+            assert_eq!(inner, 1);
+            vec![0u8; 16]
+        });
+        assert_eq!(allocations, 2);
+    }
+}
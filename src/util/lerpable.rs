@@ -1,4 +1,4 @@
-use rustfft::num_complex::Complex32;
+use realfft::num_complex::Complex32;
 
 pub trait Lerpable<Bound> {
     fn lerp(&self, from: Bound, to: Bound) -> Bound;
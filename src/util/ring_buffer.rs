@@ -12,6 +12,13 @@ impl<T: Clone> RingBuffer<T> {
             zero_offset: 0,
         }
     }
+    /// Clear the buffer and fill it with `len` copies of `value`.
+    ///
+    /// Doesn't allocate as long as `len` fits in the buffer's capacity.
+    pub fn refill(&mut self, len: usize, value: T) {
+        super::refill(&mut self.data, len, value);
+        self.zero_offset = 0;
+    }
     
     /// Clone the contents of the slice into the ring buffer,
     /// shifting forward, overwriting the old content.
@@ -33,6 +40,14 @@ impl<T> RingBuffer<T> {
             zero_offset: 0,
         }
     }
+    /// Create an empty ring buffer with room for `capacity` elements, to be
+    /// sized later with `refill`.
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            data: Vec::with_capacity(capacity),
+            zero_offset: 0,
+        }
+    }
     /// Shift the ring buffer, incrementing `zero_offset`.
     ///
     /// This means `rb[j]` becomes `rb[j-i]`
@@ -45,6 +60,9 @@ impl<T> RingBuffer<T> {
     pub fn len(&self) -> usize {
         self.data.len()
     }
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
     /// Swap the first element out for the new one, shift the buffer
     /// forward by one, and return the old first element.
     pub fn push_pop(&mut self, new_elt: T) -> T {
@@ -0,0 +1,214 @@
+use std::f64::consts::PI;
+
+/// Window functions for STFT analysis.
+///
+/// Unless noted otherwise windows are periodic (DFT-even), which is what
+/// overlap-add wants: the sample one past the end would equal the first.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WindowFunction {
+    Hann,
+    HannSymmetric,
+    Hamming,
+    Blackman,
+    BlackmanHarris,
+    Kaiser {
+        beta: f32,
+    },
+    /// `sigma` is relative to half the window length.
+    Gaussian {
+        sigma: f32,
+    },
+    FlatTop,
+    /// Square root of `Hann`, so analysis and synthesis together make a
+    /// plain Hann window.
+    SqrtHann,
+}
+
+/// Sum of cosines `a0 - a1 cos(x) + a2 cos(2x) - ...` with `x` going once
+/// around the circle over `period` samples.
+fn cosine_sum(coefficients: &[f64], i: usize, period: f64) -> f64 {
+    let x = 2.0 * PI * i as f64 / period;
+    coefficients
+        .iter()
+        .enumerate()
+        .map(|(k, a)| {
+            let sign = if k % 2 == 0 { 1.0 } else { -1.0 };
+            sign * a * (k as f64 * x).cos()
+        })
+        .sum()
+}
+
+/// Zeroth order modified Bessel function of the first kind.
+fn bessel_i0(x: f64) -> f64 {
+    let mut sum = 1.0;
+    let mut term = 1.0;
+    for k in 1..64 {
+        term *= (x / (2.0 * k as f64)).powi(2);
+        sum += term;
+        if term < sum * 1e-12 {
+            break;
+        }
+    }
+    sum
+}
+
+impl WindowFunction {
+    const HANN: &'static [f64] = &[0.5, 0.5];
+    const HAMMING: &'static [f64] = &[0.54, 0.46];
+    const BLACKMAN: &'static [f64] = &[0.42, 0.5, 0.08];
+    const BLACKMAN_HARRIS: &'static [f64] = &[0.35875, 0.48829, 0.14128, 0.01168];
+    const FLAT_TOP: &'static [f64] = &[
+        0.21557895,
+        0.41663158,
+        0.277263158,
+        0.083578947,
+        0.006947368,
+    ];
+
+    /// Fill `window` with this window function.
+    pub fn fill(self, window: &mut [f32]) {
+        let window_size = window.len();
+        let period = window_size as f64;
+        for (i, value) in window.iter_mut().enumerate() {
+            let centered = (i as f64 - period / 2.0) / (period / 2.0);
+            *value = match self {
+                Self::Hann => cosine_sum(Self::HANN, i, period),
+                Self::HannSymmetric => cosine_sum(Self::HANN, i, (period - 1.0).max(1.0)),
+                Self::Hamming => cosine_sum(Self::HAMMING, i, period),
+                Self::Blackman => cosine_sum(Self::BLACKMAN, i, period),
+                Self::BlackmanHarris => cosine_sum(Self::BLACKMAN_HARRIS, i, period),
+                Self::Kaiser { beta } => {
+                    let beta = beta as f64;
+                    bessel_i0(beta * (1.0 - centered * centered).max(0.0).sqrt()) / bessel_i0(beta)
+                }
+                Self::Gaussian { sigma } => (-0.5 * (centered / sigma as f64).powi(2)).exp(),
+                Self::FlatTop => cosine_sum(Self::FLAT_TOP, i, period),
+                Self::SqrtHann => cosine_sum(Self::HANN, i, period).sqrt(),
+            } as f32;
+        }
+    }
+}
+
+/// Derive the synthesis window that goes with `analysis` at the given hop
+/// length, so that overlap-adding `analysis * synthesis` every `hop_length`
+/// samples sums to exactly one (COLA), whatever the window.
+///
+/// This is `analysis` divided by the overlapped sum of `analysis^2`, which is
+/// the least-squares inverse of the STFT.
+pub fn fill_synthesis_window(analysis: &[f32], hop_length: usize, synthesis: &mut [f32]) {
+    debug_assert_eq!(analysis.len(), synthesis.len());
+    debug_assert!(analysis.len().is_multiple_of(hop_length));
+
+    for offset in 0..hop_length {
+        let overlap_sum: f32 = analysis[offset..]
+            .iter()
+            .step_by(hop_length)
+            .map(|w| w * w)
+            .sum();
+        for i in (offset..analysis.len()).step_by(hop_length) {
+            synthesis[i] = analysis[i] / overlap_sum;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{fill_synthesis_window, WindowFunction};
+    use crate::morpher::{AnalysisConfig, MorphSettings, Morpher};
+
+    const WINDOW_FUNCTIONS: &[WindowFunction] = &[
+        WindowFunction::Hann,
+        WindowFunction::HannSymmetric,
+        WindowFunction::Hamming,
+        WindowFunction::Blackman,
+        WindowFunction::BlackmanHarris,
+        WindowFunction::Kaiser { beta: 0.0 },
+        WindowFunction::Kaiser { beta: 8.0 },
+        WindowFunction::Gaussian { sigma: 0.4 },
+        WindowFunction::FlatTop,
+        WindowFunction::SqrtHann,
+    ];
+
+    #[test]
+    fn window_shapes() {
+        for &window_function in WINDOW_FUNCTIONS {
+            let mut window = vec![0.0; 1024];
+            window_function.fill(&mut window);
+            let peak = window.iter().cloned().fold(f32::MIN, f32::max);
+            assert!(
+                (peak - 1.0).abs() < 1e-3,
+                "{window_function:?} peaks at {peak}"
+            );
+            if window_function != WindowFunction::HannSymmetric {
+                assert!(
+                    (window[1] - window[1023]).abs() < 1e-5,
+                    "{window_function:?} isn't periodic-symmetric"
+                );
+            }
+        }
+        let mut window = vec![0.0; 1024];
+        WindowFunction::HannSymmetric.fill(&mut window);
+        assert!((window[0] - window[1023]).abs() < 1e-6);
+    }
+
+    #[test]
+    fn window_cola() {
+        // with no morph, what comes out of the STFT is what went in, delayed
+        let mut morpher = Morpher::new();
+        for &window_function in WINDOW_FUNCTIONS {
+            for window_size in [256, 1024, 4096] {
+                for overlap in [2, 4, 8, 16] {
+                    let hop_length = window_size / overlap;
+                    morpher.configure(AnalysisConfig {
+                        window_size,
+                        hop_length,
+                        window_function,
+                    });
+                    morpher.reset();
+                    let latency = morpher.latency_samples();
+
+                    let len = window_size * 3;
+                    let a: Vec<f32> = (0..len)
+                        .map(|i| {
+                            let i = i as f32;
+                            (i * 0.0123).sin() + 0.5 * (i * 0.31).sin() + 0.25 * (i * 2.9).cos()
+                        })
+                        .collect();
+                    let b: Vec<f32> = (0..len).map(|i| (i as f32 * 0.05).sin()).collect();
+                    let k_morph = vec![0.0; hop_length];
+                    let mut out = vec![(0.0, 0.0); len];
+                    for ((a, b), out) in a
+                        .chunks_exact(hop_length)
+                        .zip(b.chunks_exact(hop_length))
+                        .zip(out.chunks_exact_mut(hop_length))
+                    {
+                        morpher.morph(a, b, &k_morph, &MorphSettings::default(), out);
+                    }
+
+                    for i in window_size + latency..len {
+                        assert!(
+                            (out[i].0 - a[i - latency]).abs() < 1e-3,
+                            "{window_function:?} {window_size}/{overlap}x, sample {i}: {} != {}",
+                            out[i].0,
+                            a[i - latency]
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn window_sqrt_hann_pair() {
+        // sqrt-Hann at any overlap only needs a constant gain correction.
+        for overlap in [2, 4, 8, 16] {
+            let mut analysis = vec![0.0; 1024];
+            let mut synthesis = vec![0.0; 1024];
+            WindowFunction::SqrtHann.fill(&mut analysis);
+            fill_synthesis_window(&analysis, 1024 / overlap, &mut synthesis);
+            for (a, s) in analysis.iter().zip(synthesis.iter()) {
+                assert!((s - a * 2.0 / overlap as f32).abs() < 1e-5);
+            }
+        }
+    }
+}